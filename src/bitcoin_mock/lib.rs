@@ -0,0 +1,54 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::bitcoin::{GetUtxosRequest, GetUtxosResponse, Utxo};
+use ic_cdk_macros::*;
+use shared::utxos;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Stand-in for the management canister's Bitcoin API on a local replica. Governance is pointed at it with
+// setBitcoinCanister, and the owner seeds the outputs and chain tip each address should report.
+thread_local! {
+    static STATE: RefCell<State> = RefCell::default();
+}
+
+#[derive(CandidType, Deserialize, Default)]
+struct State {
+    owner: Option<Principal>,
+    addresses: HashMap<String, SeededAddress>,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+struct SeededAddress {
+    utxos: Vec<Utxo>,
+    tip_height: u32,
+}
+
+#[init]
+fn init(owner: Principal) {
+    STATE.with(|state| state.borrow_mut().owner = Some(owner));
+}
+
+#[update(name = "setUtxos")]
+fn set_utxos(address: String, utxos: Vec<Utxo>, tip_height: u32) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) {
+            return Err("Only the owner can seed outputs".to_string());
+        }
+        state.addresses.insert(address, SeededAddress { utxos, tip_height });
+        Ok(())
+    })
+}
+
+#[update]
+fn bitcoin_get_utxos(request: GetUtxosRequest) -> GetUtxosResponse {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let seeded = state.addresses.get(&request.address);
+        let (utxos, tip_height) = seeded.map(|seeded| (seeded.utxos.as_slice(), seeded.tip_height)).unwrap_or_default();
+        utxos::page(utxos, tip_height, request.filter)
+    })
+}
+
+ic_cdk::export_candid!();
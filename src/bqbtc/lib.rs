@@ -1,8 +1,30 @@
 use candid::{CandidType, Deserialize, Nat, Principal};
-use ic_cdk_macros::{update, query, init};
+use ic_cdk_macros::{update, query, init, pre_upgrade, post_upgrade};
+use ic_stable_structures::{StableBTreeMap, StableLog};
 use std::cell::RefCell;
 use std::collections::HashMap;
 
+use shared::icrc3;
+use shared::stable::{self, Candid, Memory};
+
+#[cfg(test)]
+mod testing;
+mod types;
+use types::{
+    Account, Allowance, AllowanceArgs, ApproveArgs, ApproveError, ArchiveInfo, Block, BlockWithId, DataCertificate,
+    GetArchivesArgs, GetBlocksArgs, GetBlocksResult, GetTransactionsResponse, MetadataValue, MinterRole, Operation,
+    RecentRequest, RecentTransaction, RolePolicy, SupportedBlockType, SupportedStandard, Transaction, TransactionWithId, TransferArg,
+    TransferError, TransferFromArgs, TransferFromError,
+};
+
+const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;
+const MAX_MEMO_LENGTH: usize = 32;
+const MAX_BLOCKS_PER_REQUEST: usize = 1000;
+const BALANCES_MEMORY: u8 = 1;
+const BLOCK_INDEX_MEMORY: u8 = 2;
+const BLOCK_DATA_MEMORY: u8 = 3;
+
 #[derive(CandidType, Debug, PartialEq, Deserialize)]
 pub enum TxError {
     InsufficientBalance,
@@ -25,9 +47,8 @@ pub struct TokenMetadata {
     symbol: String,
     decimals: u8,
     total_supply: Nat,
+    fee: Nat,
     owner: Principal,
-    pool_address: Option<Principal>,
-    cover_address: Option<Principal>,
 }
 
 impl Default for TokenMetadata {
@@ -38,137 +59,918 @@ impl Default for TokenMetadata {
             symbol: String::default(),
             decimals: 0,
             total_supply: Nat::from(0u64),
+            fee: Nat::from(0u64),
             owner: Principal::anonymous(),
-            pool_address: None,
-            cover_address: None,
         }
     }
 }
 
-#[derive(CandidType, Default)]
+#[derive(CandidType, Deserialize, Default)]
 pub struct BqBTC {
-    balances: HashMap<Principal, Nat>,
+    allowances: HashMap<(Account, Account), Allowance>,
     metadata: TokenMetadata,
+    minters: HashMap<Principal, MinterRole>,
+    role_policies: HashMap<MinterRole, RolePolicy>,
+    last_block_hash: Option<[u8; 32]>,
+    recent_transactions: Vec<RecentTransaction>,
+    // The ledger's own account, which mints are issued from and burns are returned to; it never holds a balance.
+    minting_account: Option<Account>,
+}
+
+// Layouts the token state has been saved in across upgrades; post_upgrade migrates older ones forward.
+#[derive(CandidType, Deserialize)]
+enum StableState {
+    V1(BqBTC),
 }
 
 thread_local! {
     static TOKEN: RefCell<BqBTC> = RefCell::default();
+
+    // Balances and the block log grow with every holder and transaction, so they live in stable memory
+    // instead of being copied in and out of the heap on each upgrade.
+    static BALANCES: RefCell<StableBTreeMap<Candid<Account>, Candid<Nat>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(BALANCES_MEMORY)));
+
+    static BLOCKS: StableLog<Candid<Block>, Memory, Memory> =
+        StableLog::init(stable::memory(BLOCK_INDEX_MEMORY), stable::memory(BLOCK_DATA_MEMORY))
+            .expect("Failed to initialise the block log");
+}
+
+// Checks shared by every ICRC-1/ICRC-2 request, mapped into each method's own error type.
+enum Rejection {
+    BadFee { expected_fee: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+impl From<Rejection> for TransferError {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::BadFee { expected_fee } => TransferError::BadFee { expected_fee },
+            Rejection::TooOld => TransferError::TooOld,
+            Rejection::CreatedInFuture { ledger_time } => TransferError::CreatedInFuture { ledger_time },
+            Rejection::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+            Rejection::GenericError { error_code, message } => TransferError::GenericError { error_code, message },
+        }
+    }
+}
+
+impl From<Rejection> for ApproveError {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::BadFee { expected_fee } => ApproveError::BadFee { expected_fee },
+            Rejection::TooOld => ApproveError::TooOld,
+            Rejection::CreatedInFuture { ledger_time } => ApproveError::CreatedInFuture { ledger_time },
+            Rejection::Duplicate { duplicate_of } => ApproveError::Duplicate { duplicate_of },
+            Rejection::GenericError { error_code, message } => ApproveError::GenericError { error_code, message },
+        }
+    }
+}
+
+impl From<Rejection> for TransferFromError {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::BadFee { expected_fee } => TransferFromError::BadFee { expected_fee },
+            Rejection::TooOld => TransferFromError::TooOld,
+            Rejection::CreatedInFuture { ledger_time } => TransferFromError::CreatedInFuture { ledger_time },
+            Rejection::Duplicate { duplicate_of } => TransferFromError::Duplicate { duplicate_of },
+            Rejection::GenericError { error_code, message } => TransferFromError::GenericError { error_code, message },
+        }
+    }
+}
+
+fn nat_to_usize(value: &Nat) -> usize {
+    usize::try_from(&value.0).unwrap_or(usize::MAX)
+}
+
+fn block_count() -> usize {
+    BLOCKS.with(|blocks| blocks.len() as usize)
+}
+
+fn block(id: usize) -> Option<Block> {
+    BLOCKS.with(|blocks| blocks.get(id as u64)).map(|Candid(block)| block)
+}
+
+fn default_account(owner: Principal) -> Account {
+    Account { owner, subaccount: None }
+}
+
+// The all-zero subaccount and no subaccount refer to the same ICRC-1 account.
+fn normalize(account: Account) -> Account {
+    match account.subaccount {
+        Some(subaccount) if subaccount == [0u8; 32] => default_account(account.owner),
+        _ => account,
+    }
+}
+
+impl BqBTC {
+    fn balance(&self, account: &Account) -> Nat {
+        BALANCES.with(|balances| balances.borrow().get(&Candid(normalize(*account))))
+            .map(|Candid(balance)| balance)
+            .unwrap_or_else(|| Nat::from(0u64))
+    }
+
+    fn credit(&mut self, account: Account, amount: &Nat) {
+        let balance = self.balance(&account) + amount.clone();
+        BALANCES.with(|balances| balances.borrow_mut().insert(Candid(normalize(account)), Candid(balance)));
+    }
+
+    fn debit(&mut self, account: Account, amount: &Nat) -> Result<(), Nat> {
+        let balance = self.balance(&account);
+        if balance < *amount {
+            return Err(balance);
+        }
+        BALANCES.with(|balances| balances.borrow_mut().insert(Candid(normalize(account)), Candid(balance - amount.clone())));
+        Ok(())
+    }
+
+    fn allowance(&self, account: &Account, spender: &Account, now: u64) -> Allowance {
+        match self.allowances.get(&(normalize(*account), normalize(*spender))) {
+            Some(allowance) if allowance.expires_at.map_or(true, |expires_at| expires_at > now) => allowance.clone(),
+            _ => Allowance { allowance: Nat::from(0u64), expires_at: None },
+        }
+    }
+
+    // Protocol canisters registered as minters custody LP capital and premiums and book them at face value,
+    // so the fee is waived when they pay; otherwise every payout would draw more than the pool was credited.
+    // An entry whose mint and burn rights are both revoked is no longer a protocol canister and pays the fee.
+    fn fee_for(&self, payer: &Account) -> Nat {
+        if self.policy_of(&payer.owner).map_or(false, |policy| policy.can_mint || policy.can_burn) {
+            Nat::from(0u64)
+        } else {
+            self.metadata.fee.clone()
+        }
+    }
+
+    fn policy_of(&self, minter: &Principal) -> Option<&RolePolicy> {
+        self.minters.get(minter).and_then(|role| self.role_policies.get(role))
+    }
+
+    fn rejects_minting_account(&self, to: &Account) -> Option<Rejection> {
+        (self.minting_account == Some(normalize(*to))).then(|| Rejection::GenericError {
+            error_code: Nat::from(3u64),
+            message: "Tokens cannot be sent to the minting account; burn them instead".to_string(),
+        })
+    }
+
+    // Commits the last block index and hash to the canister's certified data so icrc3_get_tip_certificate can prove them.
+    fn certify_tip(&self) {
+        if let Some(last_block_hash) = &self.last_block_hash {
+            let last_block_index = block_count() as u64 - 1;
+            ic_cdk::api::set_certified_data(&icrc3::tip_certified_data(last_block_index, last_block_hash));
+        }
+    }
+
+    fn append_block(&mut self, transaction: Transaction, effective_fee: Option<Nat>) -> Nat {
+        let block = Block { transaction, effective_fee, parent_hash: self.last_block_hash };
+        self.last_block_hash = Some(icrc3::hash_value(&icrc3::block_value(&block)));
+        let block_index = BLOCKS.with(|blocks| blocks.append(&Candid(block))).expect("Failed to append block to the log");
+        Nat::from(block_index)
+    }
+
+    fn record(&mut self, operation: Operation, now: u64) -> Nat {
+        let transaction = Transaction {
+            operation,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+            timestamp: now,
+        };
+        self.append_block(transaction, None)
+    }
+
+    fn validate(
+        &mut self,
+        request: &RecentRequest,
+        fee: &Option<Nat>,
+        expected_fee: &Nat,
+        memo: &Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+        now: u64,
+    ) -> Result<(), Rejection> {
+        if let Some(requested_fee) = fee {
+            if requested_fee != expected_fee {
+                return Err(Rejection::BadFee { expected_fee: expected_fee.clone() });
+            }
+        }
+        if memo.as_ref().map_or(false, |memo| memo.len() > MAX_MEMO_LENGTH) {
+            return Err(Rejection::GenericError {
+                error_code: Nat::from(1u64),
+                message: format!("Memo must be at most {} bytes", MAX_MEMO_LENGTH),
+            });
+        }
+
+        if let Some(created_at_time) = created_at_time {
+            if created_at_time.saturating_add(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) < now {
+                return Err(Rejection::TooOld);
+            }
+            if created_at_time > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+                return Err(Rejection::CreatedInFuture { ledger_time: now });
+            }
+
+            self.recent_transactions.retain(|recent| {
+                recent.created_at_time.saturating_add(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) >= now
+            });
+            if let Some(duplicate) = self.recent_transactions.iter().find(|recent| recent.request == *request) {
+                return Err(Rejection::Duplicate { duplicate_of: duplicate.block_index.clone() });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remember(&mut self, request: RecentRequest, created_at_time: Option<u64>, block_index: &Nat) {
+        if let Some(created_at_time) = created_at_time {
+            self.recent_transactions.push(RecentTransaction { request, created_at_time, block_index: block_index.clone() });
+        }
+    }
+
+    fn transfer(&mut self, from: Account, arg: TransferArg, now: u64) -> Result<Nat, TransferError> {
+        let from = normalize(from);
+        if let Some(rejection) = self.rejects_minting_account(&arg.to) {
+            return Err(rejection.into());
+        }
+        let request = RecentRequest::Transfer { from, arg: arg.clone() };
+        let fee = self.fee_for(&from);
+        self.validate(&request, &arg.fee, &fee, &arg.memo, arg.created_at_time, now)?;
+
+        let total = arg.amount.clone() + fee.clone();
+        self.debit(from, &total).map_err(|balance| TransferError::InsufficientFunds { balance })?;
+        self.credit(arg.to, &arg.amount);
+        self.metadata.total_supply -= fee.clone();
+
+        let transaction = Transaction {
+            operation: Operation::Transfer { from, to: arg.to, amount: arg.amount.clone(), spender: None },
+            fee: arg.fee.clone(),
+            memo: arg.memo.clone(),
+            created_at_time: arg.created_at_time,
+            timestamp: now,
+        };
+        let block_index = self.append_block(transaction, arg.fee.is_none().then_some(fee));
+        self.remember(request, arg.created_at_time, &block_index);
+        Ok(block_index)
+    }
+
+    fn approve(&mut self, from: Account, arg: ApproveArgs, now: u64) -> Result<Nat, ApproveError> {
+        let from = normalize(from);
+        let spender = normalize(arg.spender);
+        if from.owner == spender.owner {
+            return Err(ApproveError::GenericError {
+                error_code: Nat::from(2u64),
+                message: "Cannot approve an allowance to the same principal".to_string(),
+            });
+        }
+        let request = RecentRequest::Approve { from, arg: arg.clone() };
+        let fee = self.fee_for(&from);
+        self.validate(&request, &arg.fee, &fee, &arg.memo, arg.created_at_time, now)?;
+
+        if arg.expires_at.map_or(false, |expires_at| expires_at <= now) {
+            return Err(ApproveError::Expired { ledger_time: now });
+        }
+        if let Some(expected_allowance) = &arg.expected_allowance {
+            let current_allowance = self.allowance(&from, &spender, now).allowance;
+            if current_allowance != *expected_allowance {
+                return Err(ApproveError::AllowanceChanged { current_allowance });
+            }
+        }
+
+        self.debit(from, &fee).map_err(|balance| ApproveError::InsufficientFunds { balance })?;
+        self.metadata.total_supply -= fee.clone();
+
+        if arg.amount == Nat::from(0u64) {
+            self.allowances.remove(&(from, spender));
+        } else {
+            self.allowances.insert((from, spender), Allowance { allowance: arg.amount.clone(), expires_at: arg.expires_at });
+        }
+
+        let transaction = Transaction {
+            operation: Operation::Approve {
+                from,
+                spender,
+                amount: arg.amount.clone(),
+                expected_allowance: arg.expected_allowance.clone(),
+                expires_at: arg.expires_at,
+            },
+            fee: arg.fee.clone(),
+            memo: arg.memo.clone(),
+            created_at_time: arg.created_at_time,
+            timestamp: now,
+        };
+        let block_index = self.append_block(transaction, arg.fee.is_none().then_some(fee));
+        self.remember(request, arg.created_at_time, &block_index);
+        Ok(block_index)
+    }
+
+    fn transfer_from(&mut self, spender: Account, arg: TransferFromArgs, now: u64) -> Result<Nat, TransferFromError> {
+        let spender = normalize(spender);
+        let from = normalize(arg.from);
+        if let Some(rejection) = self.rejects_minting_account(&arg.to) {
+            return Err(rejection.into());
+        }
+        let request = RecentRequest::TransferFrom { spender, arg: arg.clone() };
+        let fee = self.fee_for(&from);
+        self.validate(&request, &arg.fee, &fee, &arg.memo, arg.created_at_time, now)?;
+
+        let total = arg.amount.clone() + fee.clone();
+
+        let allowance = self.allowance(&from, &spender, now);
+        if from != spender && allowance.allowance < total {
+            return Err(TransferFromError::InsufficientAllowance { allowance: allowance.allowance });
+        }
+
+        self.debit(from, &total).map_err(|balance| TransferFromError::InsufficientFunds { balance })?;
+        self.credit(arg.to, &arg.amount);
+        self.metadata.total_supply -= fee.clone();
+
+        if from != spender {
+            let remaining = allowance.allowance - total;
+            if remaining == Nat::from(0u64) {
+                self.allowances.remove(&(from, spender));
+            } else {
+                self.allowances.insert((from, spender), Allowance { allowance: remaining, expires_at: allowance.expires_at });
+            }
+        }
+
+        let transaction = Transaction {
+            operation: Operation::Transfer { from, to: arg.to, amount: arg.amount.clone(), spender: Some(spender) },
+            fee: arg.fee.clone(),
+            memo: arg.memo.clone(),
+            created_at_time: arg.created_at_time,
+            timestamp: now,
+        };
+        let block_index = self.append_block(transaction, arg.fee.is_none().then_some(fee));
+        self.remember(request, arg.created_at_time, &block_index);
+        Ok(block_index)
+    }
+
+    fn mint(&mut self, minter: Principal, to: Account, amount: Nat, now: u64) -> TxReceipt {
+        let role = *self.minters.get(&minter).ok_or(TxError::Unauthorized)?;
+        let policy = self.role_policies.get_mut(&role).ok_or(TxError::Unauthorized)?;
+        let minted = policy.minted.clone() + amount.clone();
+        if !policy.can_mint || policy.mint_cap.as_ref().map_or(false, |cap| minted > *cap) {
+            return Err(TxError::Unauthorized);
+        }
+        policy.minted = minted;
+
+        self.credit(to, &amount);
+        self.metadata.total_supply += amount.clone();
+        Ok(self.record(Operation::Mint { to, amount }, now))
+    }
+
+    // Burns from the burner's own balance, or from another account only up to the ICRC-2 allowance it granted
+    // the burner, so a burner can never destroy funds the holder has not consented to.
+    fn burn(&mut self, burner: Principal, from: Account, amount: Nat, now: u64) -> TxReceipt {
+        let role = *self.minters.get(&burner).ok_or(TxError::Unauthorized)?;
+        let policy = self.role_policies.get(&role).ok_or(TxError::Unauthorized)?;
+        let burned = policy.burned.clone() + amount.clone();
+        if !policy.can_burn || policy.burn_cap.as_ref().map_or(false, |cap| burned > *cap) {
+            return Err(TxError::Unauthorized);
+        }
+
+        let from = normalize(from);
+        let spender = default_account(burner);
+        let allowance = self.allowance(&from, &spender, now);
+        if from != spender && allowance.allowance < amount {
+            return Err(TxError::InsufficientAllowance);
+        }
+
+        self.debit(from, &amount).map_err(|_| TxError::InsufficientBalance)?;
+        if from != spender {
+            let remaining = allowance.allowance - amount.clone();
+            if remaining == Nat::from(0u64) {
+                self.allowances.remove(&(from, spender));
+            } else {
+                self.allowances.insert((from, spender), Allowance { allowance: remaining, expires_at: allowance.expires_at });
+            }
+        }
+        if let Some(policy) = self.role_policies.get_mut(&role) {
+            policy.burned = burned;
+        }
+        self.metadata.total_supply -= amount.clone();
+        Ok(self.record(Operation::Burn { from, amount }, now))
+    }
+}
+
+// Runs an update against the ledger and re-certifies the tip, which moves whenever a block is appended.
+fn update_token<T>(update: impl FnOnce(&mut BqBTC) -> T) -> T {
+    TOKEN.with(|token| {
+        let mut bqbtc = token.borrow_mut();
+        let result = update(&mut bqbtc);
+        bqbtc.certify_tip();
+        result
+    })
 }
 
 #[init]
 fn init(
     logo: String,
-    name: String, 
-    symbol: String, 
-    decimals: u8, 
-    initial_supply: Nat, 
+    name: String,
+    symbol: String,
+    decimals: u8,
+    initial_supply: Nat,
     owner: Principal
 ) {
-    let mut balances = HashMap::new();
-    balances.insert(owner, initial_supply.clone());
-    
-    let bqbtc = BqBTC {
-        balances,
+    let mut bqbtc = BqBTC {
+        allowances: HashMap::new(),
         metadata: TokenMetadata {
             logo,
             name,
             symbol,
             decimals,
-            total_supply: initial_supply,
+            total_supply: initial_supply.clone(),
+            fee: Nat::from(0u64),
             owner,
-            pool_address: None,
-            cover_address: None,
         },
+        minters: HashMap::new(),
+        role_policies: HashMap::new(),
+        last_block_hash: None,
+        recent_transactions: vec![],
+        minting_account: Some(default_account(ic_cdk::id())),
     };
+    bqbtc.credit(default_account(owner), &initial_supply);
+    bqbtc.record(Operation::Mint { to: default_account(owner), amount: initial_supply }, ic_cdk::api::time());
+    bqbtc.certify_tip();
 
     TOKEN.with(|token| *token.borrow_mut() = bqbtc);
 }
 
+#[pre_upgrade]
+fn pre_upgrade() {
+    TOKEN.with(|token| stable::save_state(&StableState::V1(std::mem::take(&mut *token.borrow_mut()))));
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let saved = match stable::restore_state() {
+        Some(StableState::V1(saved)) => saved,
+        None => {
+            ic_cdk::println!("No saved token state found, starting from an empty ledger");
+            BqBTC::default()
+        },
+    };
+    saved.certify_tip();
+    TOKEN.with(|token| *token.borrow_mut() = saved);
+}
+
 #[update]
 pub async fn transfer(to: Principal, amount: Nat) -> TxReceipt {
+    update_token(|bqbtc| {
+        let from = default_account(ic_cdk::caller());
+        let arg = TransferArg {
+            from_subaccount: None,
+            to: default_account(to),
+            amount: amount.clone(),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+
+        match bqbtc.transfer(from, arg, ic_cdk::api::time()) {
+            Ok(block_index) => Ok(block_index),
+            Err(TransferError::InsufficientFunds { .. }) => Err(TxError::InsufficientBalance),
+            Err(_) => Err(TxError::Other),
+        }
+    })
+}
+
+#[update]
+pub async fn transfer_from(from: Principal, to: Principal, amount: Nat) -> TxReceipt {
+    update_token(|bqbtc| {
+        let spender = default_account(ic_cdk::caller());
+        let arg = TransferFromArgs {
+            spender_subaccount: None,
+            from: default_account(from),
+            to: default_account(to),
+            amount: amount.clone(),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+
+        match bqbtc.transfer_from(spender, arg, ic_cdk::api::time()) {
+            Ok(block_index) => Ok(block_index),
+            Err(TransferFromError::InsufficientAllowance { .. }) => Err(TxError::InsufficientAllowance),
+            Err(TransferFromError::InsufficientFunds { .. }) => Err(TxError::InsufficientBalance),
+            Err(_) => Err(TxError::Other),
+        }
+    })
+}
+
+#[update]
+pub async fn mint(account: Principal, amount: Nat) -> TxReceipt {
+    update_token(|bqbtc| bqbtc.mint(ic_cdk::caller(), default_account(account), amount, ic_cdk::api::time()))
+}
+
+#[update]
+pub async fn burn(account: Principal, amount: Nat) -> TxReceipt {
+    update_token(|bqbtc| bqbtc.burn(ic_cdk::caller(), default_account(account), amount, ic_cdk::api::time()))
+}
+
+#[query]
+pub fn balance_of(account: Principal) -> Nat {
+    TOKEN.with(|token| token.borrow().balance(&default_account(account)))
+}
+
+#[update]
+pub fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    let from = Account { owner: ic_cdk::caller(), subaccount: arg.from_subaccount };
+    update_token(|bqbtc| bqbtc.transfer(from, arg, ic_cdk::api::time()))
+}
+
+#[query]
+pub fn icrc1_balance_of(account: Account) -> Nat {
+    TOKEN.with(|token| token.borrow().balance(&account))
+}
+
+#[query]
+pub fn icrc1_name() -> String {
+    TOKEN.with(|token| token.borrow().metadata.name.clone())
+}
+
+#[query]
+pub fn icrc1_symbol() -> String {
+    TOKEN.with(|token| token.borrow().metadata.symbol.clone())
+}
+
+#[query]
+pub fn icrc1_decimals() -> u8 {
+    TOKEN.with(|token| token.borrow().metadata.decimals)
+}
+
+#[query]
+pub fn icrc1_fee() -> Nat {
+    TOKEN.with(|token| token.borrow().metadata.fee.clone())
+}
+
+#[query]
+pub fn icrc1_total_supply() -> Nat {
+    TOKEN.with(|token| token.borrow().metadata.total_supply.clone())
+}
+
+#[query]
+pub fn icrc1_minting_account() -> Option<Account> {
+    TOKEN.with(|token| token.borrow().minting_account)
+}
+
+#[query]
+pub fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
     TOKEN.with(|token| {
-        let mut bqbtc = token.borrow_mut();
-        let from = ic_cdk::caller();
-        let zero : u64 = 0;
-        
-        if let Some(from_balance) = bqbtc.balances.get_mut(&from) {
-            if *from_balance < amount {
-                return Err(TxError::InsufficientBalance);
+        let bqbtc = token.borrow();
+        let metadata = &bqbtc.metadata;
+        vec![
+            ("icrc1:name".to_string(), MetadataValue::Text(metadata.name.clone())),
+            ("icrc1:symbol".to_string(), MetadataValue::Text(metadata.symbol.clone())),
+            ("icrc1:decimals".to_string(), MetadataValue::Nat(Nat::from(metadata.decimals))),
+            ("icrc1:fee".to_string(), MetadataValue::Nat(metadata.fee.clone())),
+            ("icrc1:logo".to_string(), MetadataValue::Text(metadata.logo.clone())),
+        ]
+    })
+}
+
+#[query]
+pub fn icrc1_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-1".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-3".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string(),
+        },
+    ]
+}
+
+#[update]
+pub fn icrc2_approve(arg: ApproveArgs) -> Result<Nat, ApproveError> {
+    let from = Account { owner: ic_cdk::caller(), subaccount: arg.from_subaccount };
+    update_token(|bqbtc| bqbtc.approve(from, arg, ic_cdk::api::time()))
+}
+
+#[update]
+pub fn icrc2_transfer_from(arg: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    let spender = Account { owner: ic_cdk::caller(), subaccount: arg.spender_subaccount };
+    update_token(|bqbtc| bqbtc.transfer_from(spender, arg, ic_cdk::api::time()))
+}
+
+#[query]
+pub fn icrc2_allowance(arg: AllowanceArgs) -> Allowance {
+    TOKEN.with(|token| token.borrow().allowance(&arg.account, &arg.spender, ic_cdk::api::time()))
+}
+
+#[query]
+pub fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let log_length = block_count();
+    let mut result = vec![];
+
+    for arg in args {
+        let start = nat_to_usize(&arg.start).min(log_length);
+        let remaining = MAX_BLOCKS_PER_REQUEST.saturating_sub(result.len());
+        let end = start.saturating_add(nat_to_usize(&arg.length).min(remaining)).min(log_length);
+
+        for id in start..end {
+            if let Some(block) = block(id) {
+                result.push(BlockWithId { id: Nat::from(id), block: icrc3::block_value(&block) });
             }
-            *from_balance -= amount.clone();
-            let to_balance = bqbtc.balances.entry(to).or_insert(Nat::from(zero));
-            *to_balance += amount.clone();
-            Ok(amount)
-        } else {
-            Err(TxError::InsufficientBalance)
         }
+    }
+
+    GetBlocksResult {
+        log_length: Nat::from(log_length),
+        blocks: result,
+        archived_blocks: vec![],
+    }
+}
+
+#[query]
+pub fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    TOKEN.with(|token| {
+        let bqbtc = token.borrow();
+        let last_block_hash = bqbtc.last_block_hash.as_ref()?;
+        let last_block_index = block_count() as u64 - 1;
+        Some(DataCertificate { certificate, hash_tree: icrc3::tip_hash_tree(last_block_index, last_block_hash) })
     })
 }
 
+// The whole block log is kept in this canister, so there are no archives to report.
+#[query]
+pub fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    vec![]
+}
+
+#[query]
+pub fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    let url = "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string();
+    ["1xfer", "1mint", "1burn", "2approve", "2xfer"]
+        .iter()
+        .map(|block_type| SupportedBlockType { block_type: block_type.to_string(), url: url.clone() })
+        .collect()
+}
+
+#[query]
+pub fn get_transactions(start: Nat, length: Nat) -> GetTransactionsResponse {
+    let log_length = block_count();
+    let start = nat_to_usize(&start).min(log_length);
+    let end = start.saturating_add(nat_to_usize(&length).min(MAX_BLOCKS_PER_REQUEST)).min(log_length);
+
+    let transactions = (start..end)
+        .filter_map(|id| block(id).map(|block| TransactionWithId { id: Nat::from(id), transaction: block.transaction }))
+        .collect();
+
+    GetTransactionsResponse {
+        log_length: Nat::from(log_length),
+        transactions,
+    }
+}
+
 #[update]
-pub async fn mint(account: Principal, amount: Nat) -> TxReceipt {
+pub fn set_fee(fee: Nat) -> Result<(), String> {
     TOKEN.with(|token| {
-        let zero : u64 = 0;
         let mut bqbtc = token.borrow_mut();
         if ic_cdk::caller() != bqbtc.metadata.owner {
-            return Err(TxError::Unauthorized);
+            return Err("Only owner can set the transfer fee".to_string());
         }
-        let balance = bqbtc.balances.entry(account).or_insert(Nat::from(zero));
-        *balance -= amount.clone();
-        bqbtc.metadata.total_supply -= amount.clone();
-        Ok(amount)
+        bqbtc.metadata.fee = fee;
+        Ok(())
     })
 }
 
+// Assigns a principal to a role; what it may mint and burn is governed by the role's policy.
 #[update]
-pub async fn burn(account: Principal, amount: Nat) -> TxReceipt {
+pub fn set_minter(minter: Principal, role: MinterRole) -> Result<(), String> {
     TOKEN.with(|token| {
         let mut bqbtc = token.borrow_mut();
         if ic_cdk::caller() != bqbtc.metadata.owner {
-            return Err(TxError::Unauthorized);
-        }
-        if let Some(balance) = bqbtc.balances.get_mut(&account) {
-            if *balance < amount {
-                return Err(TxError::InsufficientBalance);
-            }
-            *balance -= amount.clone();
-            bqbtc.metadata.total_supply -= amount.clone();
-            Ok(amount)
-        } else {
-            Err(TxError::InsufficientBalance)
+            return Err("Only owner can manage minters".to_string());
         }
+        bqbtc.minters.insert(minter, role);
+        Ok(())
     })
 }
 
-#[query]
-pub fn balance_of(account: Principal) -> Nat {
+#[update]
+pub fn remove_minter(minter: Principal) -> Result<(), String> {
     TOKEN.with(|token| {
-        let zero : u64 = 0;
-        token.borrow().balances.get(&account).cloned().unwrap_or_else(|| Nat::from(zero))
+        let mut bqbtc = token.borrow_mut();
+        if ic_cdk::caller() != bqbtc.metadata.owner {
+            return Err("Only owner can manage minters".to_string());
+        }
+        bqbtc.minters.remove(&minter).ok_or("Minter not found".to_string())?;
+        Ok(())
     })
 }
 
+// Changing a role's rights or caps keeps the totals it has already minted and burned.
 #[update]
-pub fn set_pool_and_cover(pool: Principal, cover: Principal) -> Result<(), String> {
+pub fn set_role_policy(
+    role: MinterRole,
+    can_mint: bool,
+    can_burn: bool,
+    mint_cap: Option<Nat>,
+    burn_cap: Option<Nat>
+) -> Result<(), String> {
     TOKEN.with(|token| {
         let mut bqbtc = token.borrow_mut();
         if ic_cdk::caller() != bqbtc.metadata.owner {
-            return Err("Only owner can set pool and cover addresses".to_string());
-        }
-        if bqbtc.metadata.pool_address.is_some() || bqbtc.metadata.cover_address.is_some() {
-            return Err("Pool or cover address already set".to_string());
+            return Err("Only owner can manage minters".to_string());
         }
 
-        bqbtc.metadata.pool_address = Some(pool);
-        bqbtc.metadata.cover_address = Some(cover);
+        let (minted, burned) = bqbtc.role_policies
+            .get(&role)
+            .map(|existing| (existing.minted.clone(), existing.burned.clone()))
+            .unwrap_or_else(|| (Nat::from(0u64), Nat::from(0u64)));
+
+        bqbtc.role_policies.insert(role, RolePolicy {
+            can_mint,
+            can_burn,
+            mint_cap,
+            burn_cap,
+            minted,
+            burned,
+        });
 
         Ok(())
     })
 }
 
+#[query]
+pub fn get_minters() -> Vec<(Principal, MinterRole)> {
+    TOKEN.with(|token| {
+        token.borrow().minters.iter().map(|(principal, role)| (*principal, *role)).collect()
+    })
+}
+
+#[query]
+pub fn get_role_policies() -> Vec<(MinterRole, RolePolicy)> {
+    TOKEN.with(|token| {
+        token.borrow().role_policies.iter().map(|(role, policy)| (*role, policy.clone())).collect()
+    })
+}
+
 #[query]
 pub fn get_metadata() -> TokenMetadata {
     TOKEN.with(|token| token.borrow().metadata.clone())
 }
 
-ic_cdk::export_candid!();
\ No newline at end of file
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{account, approve_args, ledger, nat, register, transfer_arg, transfer_from_args, FEE, NOW};
+
+    #[test]
+    fn retried_transfer_inside_the_window_is_a_duplicate() {
+        let mut bqbtc = ledger(&[(account(1), 1_000)]);
+        let first = bqbtc.transfer(account(1), transfer_arg(account(2), 100, Some(NOW)), NOW).unwrap();
+
+        let retry = bqbtc.transfer(account(1), transfer_arg(account(2), 100, Some(NOW)), NOW + 1);
+        assert_eq!(retry, Err(TransferError::Duplicate { duplicate_of: first }));
+        assert_eq!(bqbtc.balance(&account(2)), nat(100));
+    }
+
+    #[test]
+    fn transfers_without_created_at_time_are_never_deduplicated() {
+        let mut bqbtc = ledger(&[(account(1), 1_000)]);
+        bqbtc.transfer(account(1), transfer_arg(account(2), 100, None), NOW).unwrap();
+        bqbtc.transfer(account(1), transfer_arg(account(2), 100, None), NOW).unwrap();
+        assert_eq!(bqbtc.balance(&account(2)), nat(200));
+    }
+
+    #[test]
+    fn transfers_outside_the_window_are_rejected() {
+        let mut bqbtc = ledger(&[(account(1), 1_000)]);
+        let too_old = NOW - TX_WINDOW_NANOS - PERMITTED_DRIFT_NANOS - 1;
+        assert_eq!(bqbtc.transfer(account(1), transfer_arg(account(2), 100, Some(too_old)), NOW), Err(TransferError::TooOld));
+
+        let in_future = NOW + PERMITTED_DRIFT_NANOS + 1;
+        assert_eq!(
+            bqbtc.transfer(account(1), transfer_arg(account(2), 100, Some(in_future)), NOW),
+            Err(TransferError::CreatedInFuture { ledger_time: NOW })
+        );
+    }
+
+    #[test]
+    fn transfer_charges_the_fee_unless_the_payer_is_an_active_minter() {
+        let mut bqbtc = ledger(&[(account(1), 1_000), (account(3), 1_000)]);
+        bqbtc.transfer(account(1), transfer_arg(account(2), 100, None), NOW).unwrap();
+        assert_eq!(bqbtc.balance(&account(1)), nat(1_000 - 100 - FEE));
+
+        register(&mut bqbtc, &account(3), MinterRole::Pool, true, false, None);
+        bqbtc.transfer(account(3), transfer_arg(account(2), 100, None), NOW).unwrap();
+        assert_eq!(bqbtc.balance(&account(3)), nat(900));
+
+        register(&mut bqbtc, &account(3), MinterRole::Pool, false, false, None);
+        bqbtc.transfer(account(3), transfer_arg(account(2), 100, None), NOW).unwrap();
+        assert_eq!(bqbtc.balance(&account(3)), nat(800 - FEE));
+    }
+
+    #[test]
+    fn transfer_to_the_minting_account_is_rejected() {
+        let mut bqbtc = ledger(&[(account(1), 1_000)]);
+        let result = bqbtc.transfer(account(1), transfer_arg(account(0), 100, None), NOW);
+        assert!(matches!(result, Err(TransferError::GenericError { .. })));
+    }
+
+    #[test]
+    fn expired_allowance_cannot_be_spent() {
+        let mut bqbtc = ledger(&[(account(1), 1_000)]);
+        bqbtc.approve(account(1), approve_args(account(2), 500, Some(NOW + 10)), NOW).unwrap();
+        assert_eq!(bqbtc.allowance(&account(1), &account(2), NOW + 9).allowance, nat(500));
+        assert_eq!(bqbtc.allowance(&account(1), &account(2), NOW + 10).allowance, nat(0));
+
+        let result = bqbtc.transfer_from(account(2), transfer_from_args(account(1), account(3), 100), NOW + 10);
+        assert_eq!(result, Err(TransferFromError::InsufficientAllowance { allowance: nat(0) }));
+    }
+
+    #[test]
+    fn approval_that_has_already_expired_is_rejected() {
+        let mut bqbtc = ledger(&[(account(1), 1_000)]);
+        let result = bqbtc.approve(account(1), approve_args(account(2), 500, Some(NOW)), NOW);
+        assert_eq!(result, Err(ApproveError::Expired { ledger_time: NOW }));
+    }
+
+    #[test]
+    fn approval_with_a_stale_expected_allowance_is_rejected() {
+        let mut bqbtc = ledger(&[(account(1), 1_000)]);
+        bqbtc.approve(account(1), approve_args(account(2), 500, None), NOW).unwrap();
+
+        let stale = ApproveArgs { expected_allowance: Some(nat(100)), ..approve_args(account(2), 50, None) };
+        assert_eq!(bqbtc.approve(account(1), stale, NOW), Err(ApproveError::AllowanceChanged { current_allowance: nat(500) }));
+
+        let current = ApproveArgs { expected_allowance: Some(nat(500)), ..approve_args(account(2), 50, None) };
+        bqbtc.approve(account(1), current, NOW).unwrap();
+        assert_eq!(bqbtc.allowance(&account(1), &account(2), NOW).allowance, nat(50));
+    }
+
+    #[test]
+    fn transfer_from_spends_the_amount_and_fee_from_the_allowance() {
+        let mut bqbtc = ledger(&[(account(1), 1_000)]);
+        bqbtc.approve(account(1), approve_args(account(2), 500, None), NOW).unwrap();
+        bqbtc.transfer_from(account(2), transfer_from_args(account(1), account(3), 100), NOW).unwrap();
+
+        assert_eq!(bqbtc.allowance(&account(1), &account(2), NOW).allowance, nat(500 - 100 - FEE));
+        assert_eq!(bqbtc.balance(&account(3)), nat(100));
+    }
+
+    #[test]
+    fn burner_needs_the_holders_allowance_to_burn_their_tokens() {
+        let mut bqbtc = ledger(&[(account(1), 1_000)]);
+        register(&mut bqbtc, &account(2), MinterRole::Cover, false, true, None);
+        let burner = account(2).owner;
+
+        assert_eq!(bqbtc.burn(burner, account(1), nat(100), NOW), Err(TxError::InsufficientAllowance));
+
+        bqbtc.approve(account(1), approve_args(account(2), 300, None), NOW).unwrap();
+        bqbtc.burn(burner, account(1), nat(100), NOW).unwrap();
+        assert_eq!(bqbtc.balance(&account(1)), nat(1_000 - FEE - 100));
+        assert_eq!(bqbtc.allowance(&account(1), &account(2), NOW).allowance, nat(200));
+        assert_eq!(bqbtc.metadata.total_supply, nat(1_000 - FEE - 100));
+    }
+
+    #[test]
+    fn burner_can_burn_its_own_balance_without_an_allowance() {
+        let mut bqbtc = ledger(&[(account(2), 1_000)]);
+        register(&mut bqbtc, &account(2), MinterRole::Cover, false, true, None);
+        bqbtc.burn(account(2).owner, account(2), nat(400), NOW).unwrap();
+        assert_eq!(bqbtc.balance(&account(2)), nat(600));
+    }
+
+    #[test]
+    fn role_without_burn_rights_cannot_burn() {
+        let mut bqbtc = ledger(&[(account(2), 1_000)]);
+        register(&mut bqbtc, &account(2), MinterRole::Pool, true, false, None);
+        assert_eq!(bqbtc.burn(account(2).owner, account(2), nat(1), NOW), Err(TxError::Unauthorized));
+        assert_eq!(bqbtc.burn(account(4).owner, account(4), nat(1), NOW), Err(TxError::Unauthorized));
+    }
+
+    #[test]
+    fn principals_sharing_a_role_share_its_mint_cap() {
+        let mut bqbtc = ledger(&[]);
+        register(&mut bqbtc, &account(2), MinterRole::Pool, true, false, Some(150));
+        bqbtc.minters.insert(account(3).owner, MinterRole::Pool);
+
+        bqbtc.mint(account(2).owner, account(5), nat(100), NOW).unwrap();
+        assert_eq!(bqbtc.mint(account(3).owner, account(5), nat(100), NOW), Err(TxError::Unauthorized));
+        bqbtc.mint(account(3).owner, account(5), nat(50), NOW).unwrap();
+
+        assert_eq!(bqbtc.balance(&account(5)), nat(150));
+        assert_eq!(bqbtc.role_policies[&MinterRole::Pool].minted, nat(150));
+    }
+
+    #[test]
+    fn burn_cap_bounds_the_roles_running_total() {
+        let mut bqbtc = ledger(&[(account(2), 1_000)]);
+        register(&mut bqbtc, &account(2), MinterRole::Governance, false, true, Some(300));
+        bqbtc.burn(account(2).owner, account(2), nat(200), NOW).unwrap();
+        assert_eq!(bqbtc.burn(account(2).owner, account(2), nat(200), NOW), Err(TxError::Unauthorized));
+        assert_eq!(bqbtc.balance(&account(2)), nat(800));
+    }
+}
@@ -0,0 +1,64 @@
+// Fixtures shared by the bqBTC ledger's unit tests.
+use candid::{Nat, Principal};
+
+use crate::types::{Account, ApproveArgs, MinterRole, RolePolicy, TransferArg, TransferFromArgs};
+use crate::{default_account, BqBTC, TokenMetadata};
+
+pub const FEE: u64 = 10;
+pub const NOW: u64 = 1_700_000_000_000_000_000;
+
+pub fn nat(value: u64) -> Nat {
+    Nat::from(value)
+}
+
+pub fn account(id: u8) -> Account {
+    default_account(Principal::from_slice(&[id]))
+}
+
+// A ledger charging FEE per transfer, with account(0) as its minting account and `balances` already minted.
+pub fn ledger(balances: &[(Account, u64)]) -> BqBTC {
+    let mut bqbtc = BqBTC {
+        metadata: TokenMetadata { fee: nat(FEE), ..TokenMetadata::default() },
+        minting_account: Some(account(0)),
+        ..BqBTC::default()
+    };
+    for (holder, balance) in balances {
+        bqbtc.credit(*holder, &nat(*balance));
+        bqbtc.metadata.total_supply += nat(*balance);
+    }
+    bqbtc
+}
+
+// Registers `holder` under `role` with the given rights and caps for the role.
+pub fn register(bqbtc: &mut BqBTC, holder: &Account, role: MinterRole, can_mint: bool, can_burn: bool, cap: Option<u64>) {
+    bqbtc.minters.insert(holder.owner, role);
+    bqbtc.role_policies.insert(role, RolePolicy {
+        can_mint,
+        can_burn,
+        mint_cap: cap.map(nat),
+        burn_cap: cap.map(nat),
+        minted: nat(0),
+        burned: nat(0),
+    });
+}
+
+pub fn transfer_arg(to: Account, amount: u64, created_at_time: Option<u64>) -> TransferArg {
+    TransferArg { from_subaccount: None, to, amount: nat(amount), fee: None, memo: None, created_at_time }
+}
+
+pub fn approve_args(spender: Account, amount: u64, expires_at: Option<u64>) -> ApproveArgs {
+    ApproveArgs {
+        from_subaccount: None,
+        spender,
+        amount: nat(amount),
+        expected_allowance: None,
+        expires_at,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    }
+}
+
+pub fn transfer_from_args(from: Account, to: Account, amount: u64) -> TransferFromArgs {
+    TransferFromArgs { spender_subaccount: None, from, to, amount: nat(amount), fee: None, memo: None, created_at_time: None }
+}
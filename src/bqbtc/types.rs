@@ -0,0 +1,136 @@
+use candid::{CandidType, Deserialize, Int, Nat};
+
+pub use shared::icrc3::{
+    Account, ArchiveInfo, Block, BlockWithId, DataCertificate, GetArchivesArgs, GetBlocksArgs, GetBlocksResult,
+    GetTransactionsResponse, Operation, Subaccount, SupportedBlockType, Transaction, TransactionWithId,
+};
+
+#[derive(Clone, CandidType, Deserialize, PartialEq)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>)
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String
+}
+
+#[derive(Clone, CandidType, Deserialize, PartialEq)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String }
+}
+
+#[derive(Clone, CandidType, Deserialize, PartialEq)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>
+}
+
+#[derive(Clone, CandidType, Deserialize, PartialEq)]
+pub enum RecentRequest {
+    Transfer { from: Account, arg: TransferArg },
+    Approve { from: Account, arg: ApproveArgs },
+    TransferFrom { spender: Account, arg: TransferFromArgs }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct RecentTransaction {
+    pub request: RecentRequest,
+    pub created_at_time: u64,
+    pub block_index: Nat
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq, Eq, Hash)]
+pub enum MinterRole {
+    Pool,
+    Cover,
+    Governance
+}
+
+// What every principal holding a role may mint and burn. The caps bound the role's running totals, so
+// registering a second canister under a role shares the role's budget instead of doubling it.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct RolePolicy {
+    pub can_mint: bool,
+    pub can_burn: bool,
+    pub mint_cap: Option<Nat>,
+    pub burn_cap: Option<Nat>,
+    pub minted: Nat,
+    pub burned: Nat
+}
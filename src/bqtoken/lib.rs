@@ -1,8 +1,24 @@
 use candid::{CandidType, Deserialize, Nat, Principal};
-use ic_cdk_macros::{update, query, init};
+use ic_cdk_macros::{update, query, init, pre_upgrade, post_upgrade};
+use ic_stable_structures::{StableBTreeMap, StableLog};
 use std::cell::RefCell;
 use std::collections::HashMap;
 
+use shared::icrc3;
+use shared::stable::{self, Candid, Memory};
+
+mod types;
+use types::{
+    Account, ArchiveInfo, Block, BlockWithId, Checkpoint, DataCertificate, GetArchivesArgs, GetBlocksArgs, GetBlocksResult,
+    GetTransactionsResponse, Operation, SupportedBlockType, Transaction, TransactionWithId,
+};
+
+const MAX_BLOCKS_PER_REQUEST: usize = 1000;
+const BALANCES_MEMORY: u8 = 1;
+const BLOCK_INDEX_MEMORY: u8 = 2;
+const BLOCK_DATA_MEMORY: u8 = 3;
+const CHECKPOINTS_MEMORY: u8 = 4;
+
 #[derive(CandidType, Debug, PartialEq, Deserialize)]
 pub enum TxError {
     InsufficientBalance,
@@ -45,14 +61,112 @@ impl Default for TokenMetadata {
     }
 }
 
-#[derive(CandidType, Default)]
+#[derive(CandidType, Deserialize, Default)]
 pub struct BQToken {
-    balances: HashMap<Principal, Nat>,
+    allowances: HashMap<(Principal, Principal), Nat>,
     metadata: TokenMetadata,
+    last_block_hash: Option<[u8; 32]>,
+}
+
+// Layouts the token state has been saved in across upgrades; post_upgrade migrates older ones forward.
+#[derive(CandidType, Deserialize)]
+enum StableState {
+    V1(BQToken),
 }
 
 thread_local! {
     static TOKEN: RefCell<BQToken> = RefCell::default();
+
+    // Balances and the block log grow with every holder and transaction, so they live in stable memory
+    // instead of being copied in and out of the heap on each upgrade.
+    static BALANCES: RefCell<StableBTreeMap<Principal, Candid<Nat>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(BALANCES_MEMORY)));
+
+    static BLOCKS: StableLog<Candid<Block>, Memory, Memory> =
+        StableLog::init(stable::memory(BLOCK_INDEX_MEMORY), stable::memory(BLOCK_DATA_MEMORY))
+            .expect("Failed to initialise the block log");
+
+    // Each holder's balance after every block that touched it, keyed by holder and block index.
+    static CHECKPOINTS: RefCell<StableBTreeMap<(Principal, u64), Candid<Checkpoint>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(CHECKPOINTS_MEMORY)));
+}
+
+fn nat_to_usize(value: &Nat) -> usize {
+    usize::try_from(&value.0).unwrap_or(usize::MAX)
+}
+
+fn balance(owner: &Principal) -> Nat {
+    BALANCES.with(|balances| balances.borrow().get(owner))
+        .map(|Candid(balance)| balance)
+        .unwrap_or_else(|| Nat::from(0u64))
+}
+
+fn set_balance(owner: Principal, balance: Nat) {
+    BALANCES.with(|balances| balances.borrow_mut().insert(owner, Candid(balance)));
+}
+
+fn block_count() -> usize {
+    BLOCKS.with(|blocks| blocks.len() as usize)
+}
+
+fn block(id: usize) -> Option<Block> {
+    BLOCKS.with(|blocks| blocks.get(id as u64)).map(|Candid(block)| block)
+}
+
+fn account(owner: Principal) -> Account {
+    Account { owner, subaccount: None }
+}
+
+// A holder's checkpoints are stored in block order and timestamps only grow with the block index, so the
+// balance at a point is the last checkpoint before the first one past it.
+fn balance_at(owner: Principal, is_before: impl Fn(u64, &Checkpoint) -> bool) -> Nat {
+    CHECKPOINTS.with(|checkpoints| {
+        checkpoints.borrow()
+            .range((owner, 0)..)
+            .take_while(|((holder, block_index), Candid(checkpoint))| *holder == owner && is_before(*block_index, checkpoint))
+            .last()
+            .map(|(_, Candid(checkpoint))| checkpoint.amount)
+            .unwrap_or_else(|| Nat::from(0u64))
+    })
+}
+
+impl BQToken {
+    // Commits the last block index and hash to the canister's certified data so icrc3_get_tip_certificate can prove them.
+    fn certify_tip(&self) {
+        if let Some(last_block_hash) = &self.last_block_hash {
+            let last_block_index = block_count() as u64 - 1;
+            ic_cdk::api::set_certified_data(&icrc3::tip_certified_data(last_block_index, last_block_hash));
+        }
+    }
+
+    fn record(&mut self, operation: Operation) -> Nat {
+        let transaction = Transaction {
+            operation,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+            timestamp: ic_cdk::api::time(),
+        };
+        let timestamp = transaction.timestamp;
+        let affected = match &transaction.operation {
+            Operation::Mint { to, .. } => vec![to.owner],
+            Operation::Burn { from, .. } => vec![from.owner],
+            Operation::Transfer { from, to, .. } => vec![from.owner, to.owner],
+            Operation::Approve { .. } => vec![],
+        };
+
+        let block = Block { transaction, effective_fee: None, parent_hash: self.last_block_hash };
+        self.last_block_hash = Some(icrc3::hash_value(&icrc3::block_value(&block)));
+        let block_index = BLOCKS.with(|blocks| blocks.append(&Candid(block))).expect("Failed to append block to the log");
+        self.certify_tip();
+
+        for owner in affected {
+            let checkpoint = Checkpoint { timestamp, amount: balance(&owner) };
+            CHECKPOINTS.with(|checkpoints| checkpoints.borrow_mut().insert((owner, block_index), Candid(checkpoint)));
+        }
+
+        Nat::from(block_index)
+    }
 }
 
 #[init]
@@ -64,88 +178,165 @@ fn init(
     initial_supply: Nat, 
     owner: Principal
 ) {
-    let mut balances = HashMap::new();
-    balances.insert(owner, initial_supply.clone());
-    
-    let bqtoken = BQToken {
-        balances,
+    let mut bqtoken = BQToken {
+        allowances: HashMap::new(),
         metadata: TokenMetadata {
             logo,
             name,
             symbol,
             decimals,
-            total_supply: initial_supply,
+            total_supply: initial_supply.clone(),
             owner,
             pool_address: None,
             cover_address: None,
         },
+        last_block_hash: None,
     };
+    set_balance(owner, initial_supply.clone());
+    bqtoken.record(Operation::Mint { to: account(owner), amount: initial_supply });
 
     TOKEN.with(|token| *token.borrow_mut() = bqtoken);
 }
 
+#[pre_upgrade]
+fn pre_upgrade() {
+    TOKEN.with(|token| stable::save_state(&StableState::V1(std::mem::take(&mut *token.borrow_mut()))));
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let saved = match stable::restore_state() {
+        Some(StableState::V1(saved)) => saved,
+        None => {
+            ic_cdk::println!("No saved token state found, starting from an empty ledger");
+            BQToken::default()
+        },
+    };
+    saved.certify_tip();
+    TOKEN.with(|token| *token.borrow_mut() = saved);
+}
+
 #[update]
 async fn transfer(to: Principal, amount: Nat) -> TxReceipt {
     TOKEN.with(|token| {
         let mut bqtoken = token.borrow_mut();
         let from = ic_cdk::caller();
+
+        let from_balance = balance(&from);
+        if from_balance < amount {
+            return Err(TxError::InsufficientBalance);
+        }
+        set_balance(from, from_balance - amount.clone());
+        set_balance(to, balance(&to) + amount.clone());
+        Ok(bqtoken.record(Operation::Transfer { from: account(from), to: account(to), amount, spender: None }))
+    })
+}
+
+#[update]
+fn approve(spender: Principal, amount: Nat) -> TxReceipt {
+    TOKEN.with(|token| {
+        let mut bqtoken = token.borrow_mut();
+        let owner = ic_cdk::caller();
+        if owner == spender {
+            return Err(TxError::ErrorTo);
+        }
+
+        if amount == Nat::from(0u64) {
+            bqtoken.allowances.remove(&(owner, spender));
+        } else {
+            bqtoken.allowances.insert((owner, spender), amount.clone());
+        }
+        Ok(bqtoken.record(Operation::Approve {
+            from: account(owner),
+            spender: account(spender),
+            amount,
+            expected_allowance: None,
+            expires_at: None,
+        }))
+    })
+}
+
+#[update(name = "transferFrom")]
+fn transfer_from(from: Principal, to: Principal, amount: Nat) -> TxReceipt {
+    TOKEN.with(|token| {
+        let mut bqtoken = token.borrow_mut();
+        let spender = ic_cdk::caller();
         let zero : u64 = 0;
-        
-        if let Some(from_balance) = bqtoken.balances.get_mut(&from) {
-            if *from_balance < amount {
-                return Err(TxError::InsufficientBalance);
-            }
-            *from_balance -= amount.clone();
-            let to_balance = bqtoken.balances.entry(to).or_insert(Nat::from(zero));
-            *to_balance += amount.clone();
-            Ok(amount)
+
+        let allowance = bqtoken.allowances.get(&(from, spender)).cloned().unwrap_or_else(|| Nat::from(zero));
+        if allowance < amount {
+            return Err(TxError::InsufficientAllowance);
+        }
+        let from_balance = balance(&from);
+        if from_balance < amount {
+            return Err(TxError::InsufficientBalance);
+        }
+
+        set_balance(from, from_balance - amount.clone());
+        set_balance(to, balance(&to) + amount.clone());
+        let remaining = allowance - amount.clone();
+        if remaining == Nat::from(zero) {
+            bqtoken.allowances.remove(&(from, spender));
         } else {
-            Err(TxError::InsufficientBalance)
+            bqtoken.allowances.insert((from, spender), remaining);
         }
+
+        Ok(bqtoken.record(Operation::Transfer { from: account(from), to: account(to), amount, spender: Some(account(spender)) }))
     })
 }
 
-#[update]
-fn mint(account: Principal, amount: Nat) -> TxReceipt {
+#[query]
+fn allowance(owner: Principal, spender: Principal) -> Nat {
     TOKEN.with(|token| {
         let zero : u64 = 0;
+        token.borrow().allowances.get(&(owner, spender)).cloned().unwrap_or_else(|| Nat::from(zero))
+    })
+}
+
+#[update]
+fn mint(to: Principal, amount: Nat) -> TxReceipt {
+    TOKEN.with(|token| {
         let mut bqtoken = token.borrow_mut();
         if ic_cdk::caller() != bqtoken.metadata.owner {
             return Err(TxError::Unauthorized);
         }
-        let balance = bqtoken.balances.entry(account).or_insert(Nat::from(zero));
-        *balance -= amount.clone();
-        bqtoken.metadata.total_supply -= amount.clone();
-        Ok(amount)
+        set_balance(to, balance(&to) + amount.clone());
+        bqtoken.metadata.total_supply += amount.clone();
+        Ok(bqtoken.record(Operation::Mint { to: account(to), amount }))
     })
 }
 
 #[update]
-fn burn(account: Principal, amount: Nat) -> TxReceipt {
+fn burn(from: Principal, amount: Nat) -> TxReceipt {
     TOKEN.with(|token| {
         let mut bqtoken = token.borrow_mut();
         if ic_cdk::caller() != bqtoken.metadata.owner {
             return Err(TxError::Unauthorized);
         }
-        if let Some(balance) = bqtoken.balances.get_mut(&account) {
-            if *balance < amount {
-                return Err(TxError::InsufficientBalance);
-            }
-            *balance -= amount.clone();
-            bqtoken.metadata.total_supply -= amount.clone();
-            Ok(amount)
-        } else {
-            Err(TxError::InsufficientBalance)
+        let from_balance = balance(&from);
+        if from_balance < amount {
+            return Err(TxError::InsufficientBalance);
         }
+        set_balance(from, from_balance - amount.clone());
+        bqtoken.metadata.total_supply -= amount.clone();
+        Ok(bqtoken.record(Operation::Burn { from: account(from), amount }))
     })
 }
 
 #[query(name = "balanceOf")]
 fn balance_of(account: Principal) -> Nat {
-    TOKEN.with(|token| {
-        let zero : u64 = 0;
-        token.borrow().balances.get(&account).cloned().unwrap_or_else(|| Nat::from(zero))
-    })
+    balance(&account)
+}
+
+#[query(name = "balanceOfAt")]
+fn balance_of_at(account: Principal, timestamp: u64) -> Nat {
+    balance_at(account, |_, checkpoint| checkpoint.timestamp <= timestamp)
+}
+
+#[query(name = "balanceOfAtBlock")]
+fn balance_of_at_block(account: Principal, block_index: Nat) -> Nat {
+    let block_index = u64::try_from(&block_index.0).unwrap_or(u64::MAX);
+    balance_at(account, |checkpoint_block, _| checkpoint_block <= block_index)
 }
 
 #[query]
@@ -153,4 +344,70 @@ fn get_metadata() -> TokenMetadata {
     TOKEN.with(|token| token.borrow().metadata.clone())
 }
 
+#[query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let log_length = block_count();
+    let mut result = vec![];
+
+    for arg in args {
+        let start = nat_to_usize(&arg.start).min(log_length);
+        let remaining = MAX_BLOCKS_PER_REQUEST.saturating_sub(result.len());
+        let end = start.saturating_add(nat_to_usize(&arg.length).min(remaining)).min(log_length);
+
+        for id in start..end {
+            if let Some(block) = block(id) {
+                result.push(BlockWithId { id: Nat::from(id), block: icrc3::block_value(&block) });
+            }
+        }
+    }
+
+    GetBlocksResult {
+        log_length: Nat::from(log_length),
+        blocks: result,
+        archived_blocks: vec![],
+    }
+}
+
+#[query]
+fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    TOKEN.with(|token| {
+        let bqtoken = token.borrow();
+        let last_block_hash = bqtoken.last_block_hash.as_ref()?;
+        let last_block_index = block_count() as u64 - 1;
+        Some(DataCertificate { certificate, hash_tree: icrc3::tip_hash_tree(last_block_index, last_block_hash) })
+    })
+}
+
+// The whole block log is kept in this canister, so there are no archives to report.
+#[query]
+fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    vec![]
+}
+
+#[query]
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    let url = "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string();
+    ["1xfer", "1mint", "1burn", "2approve", "2xfer"]
+        .iter()
+        .map(|block_type| SupportedBlockType { block_type: block_type.to_string(), url: url.clone() })
+        .collect()
+}
+
+#[query]
+fn get_transactions(start: Nat, length: Nat) -> GetTransactionsResponse {
+    let log_length = block_count();
+    let start = nat_to_usize(&start).min(log_length);
+    let end = start.saturating_add(nat_to_usize(&length).min(MAX_BLOCKS_PER_REQUEST)).min(log_length);
+
+    let transactions = (start..end)
+        .filter_map(|id| block(id).map(|block| TransactionWithId { id: Nat::from(id), transaction: block.transaction }))
+        .collect();
+
+    GetTransactionsResponse {
+        log_length: Nat::from(log_length),
+        transactions,
+    }
+}
+
 ic_cdk::export_candid!();
\ No newline at end of file
@@ -0,0 +1,12 @@
+use candid::{CandidType, Deserialize, Nat};
+
+pub use shared::icrc3::{
+    Account, ArchiveInfo, Block, BlockWithId, DataCertificate, GetArchivesArgs, GetBlocksArgs, GetBlocksResult,
+    GetTransactionsResponse, Operation, SupportedBlockType, Transaction, TransactionWithId,
+};
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Checkpoint {
+    pub timestamp: u64,
+    pub amount: Nat
+}
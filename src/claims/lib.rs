@@ -0,0 +1,455 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::call::call;
+use ic_cdk_macros::*;
+use shared::stable;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+mod payout;
+#[cfg(test)]
+mod testing;
+mod types;
+use types::{Claim, ClaimStatus, Cover, GenericCoverInfo, Payout, Proposal, ProposalParam, ProposalStatus, ProtocolCanisters};
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::default();
+    // Claims whose payout call chain is currently awaiting another canister. Not persisted: an upgrade
+    // requires the canister to be stopped, which drains every outstanding call first.
+    static PAYOUTS_IN_FLIGHT: RefCell<HashSet<Nat>> = RefCell::default();
+}
+
+#[derive(CandidType, Deserialize, Default)]
+struct State {
+    claims: HashMap<Nat, Claim>,
+    claim_count: Nat,
+    open_claims: HashMap<(Principal, Nat), Nat>,
+    user_claims: HashMap<Principal, Vec<Nat>>,
+    owner: Option<Principal>,
+    gov_address: Option<Principal>,
+    cover_address: Option<Principal>,
+    pool_address: Option<Principal>,
+    registry: Option<Principal>,
+    registry_version: u64,
+}
+
+// Layouts the state has been saved in across upgrades; post_upgrade migrates older ones forward.
+#[derive(CandidType, Deserialize)]
+enum StableState {
+    V1(State),
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CanisterIds {
+    governance: Option<Principal>,
+    cover: Option<Principal>,
+    pool: Option<Principal>,
+}
+
+// User-facing endpoints refuse to run until every peer canister has been wired up.
+fn ensure_configured(state: &State) -> Result<(), String> {
+    let missing: Vec<&str> = [
+        ("governance", state.gov_address),
+        ("cover", state.cover_address),
+        ("pool", state.pool_address),
+    ]
+    .iter()
+    .filter(|(_, id)| id.is_none())
+    .map(|(name, _)| *name)
+    .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Claims canister is not configured: missing {}", missing.join(", ")))
+    }
+}
+
+#[init]
+fn init(owner: Principal, governance: Principal, cover: Principal, pool: Principal) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.owner = Some(owner);
+        state.gov_address = Some(governance);
+        state.cover_address = Some(cover);
+        state.pool_address = Some(pool);
+    });
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    STATE.with(|state| stable::save_state(&StableState::V1(std::mem::take(&mut *state.borrow_mut()))));
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let saved = match stable::restore_state() {
+        Some(StableState::V1(saved)) => saved,
+        None => {
+            ic_cdk::println!("No saved state found, starting from an empty state");
+            State::default()
+        },
+    };
+    STATE.with(|state| *state.borrow_mut() = saved);
+    if STATE.with(|state| state.borrow().registry.is_some()) {
+        schedule_registry_sync();
+    }
+}
+
+fn close_claim(state: &mut State, claim_id: &Nat, status: ClaimStatus) -> Result<(), String> {
+    let claim = state.claims.get_mut(claim_id).ok_or("Claim not found".to_string())?;
+    claim.status = status;
+    let key = (claim.user, claim.cover_id.clone());
+    if state.open_claims.get(&key) == Some(claim_id) {
+        state.open_claims.remove(&key);
+    }
+    Ok(())
+}
+
+// Held for the whole payout of a claim so a concurrent retry cannot pay it or settle the cover twice.
+// Dropping it, including during the cleanup of a trapped callback, releases the claim.
+struct PayoutGuard {
+    claim_id: Nat,
+}
+
+impl PayoutGuard {
+    fn acquire(claim_id: &Nat) -> Result<Self, String> {
+        PAYOUTS_IN_FLIGHT.with(|in_flight| {
+            if !in_flight.borrow_mut().insert(claim_id.clone()) {
+                return Err("Claim payout already in progress".to_string());
+            }
+            Ok(PayoutGuard { claim_id: claim_id.clone() })
+        })
+    }
+}
+
+impl Drop for PayoutGuard {
+    fn drop(&mut self) {
+        PAYOUTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.claim_id));
+    }
+}
+
+// Applies `update` to the stored claim and returns the updated copy.
+fn record_claim(claim_id: &Nat, update: impl FnOnce(&mut Claim)) -> Result<Claim, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let claim = state.claims.get_mut(claim_id).ok_or("Claim not found".to_string())?;
+        update(claim);
+        Ok(claim.clone())
+    })
+}
+
+async fn get_proposal(gov_canister: Principal, proposal_id: Nat) -> Result<Proposal, String> {
+    let proposal_details: Result<(Result<Proposal, String>,), _> = call(gov_canister, "getProposalDetails", (proposal_id,)).await;
+    let (proposal,) = proposal_details.map_err(|_| "Failed to get proposal details".to_string())?;
+    proposal
+}
+
+#[update(name = "fileClaim")]
+pub async fn file_claim(cover_id: Nat, amount: Nat, tx_hash: String, btc_address: String, description: String) -> Result<Nat, String> {
+    let caller = ic_cdk::caller();
+    let (gov_canister, cover_canister) = STATE.with(|state| {
+        let state = state.borrow();
+        ensure_configured(&state)?;
+        if state.open_claims.contains_key(&(caller, cover_id.clone())) {
+            return Err("An open claim already exists for this cover".to_string());
+        }
+        let gov_canister = state.gov_address.ok_or("Governance canister address not set")?;
+        let cover_canister = state.cover_address.ok_or("Cover canister address not set")?;
+        Ok::<(Principal, Principal), String>((gov_canister, cover_canister))
+    })?;
+
+    let cover_info: Result<(Result<GenericCoverInfo, String>,), _> = call(cover_canister, "getUserCoverInfo", (caller, cover_id.clone())).await;
+    let (cover,) = cover_info.map_err(|_| "Failed to retrieve cover info".to_string())?;
+    let cover = cover?;
+    if !cover.is_active {
+        return Err("Cover is not active".to_string());
+    }
+    if Nat::from(ic_cdk::api::time() / 1_000_000_000) > cover.end_day {
+        return Err("Cover has expired".to_string());
+    }
+    if amount > cover.cover_value {
+        return Err("Claim amount exceeds cover value".to_string());
+    }
+
+    // The pool that pays is always the one backing the cover, never one named by the claimant.
+    let cover_details: Result<(Result<Cover, String>,), _> = call(cover_canister, "getCoverInfo", (cover_id.clone(),)).await;
+    let (cover_details,) = cover_details.map_err(|_| "Failed to retrieve cover details".to_string())?;
+    let pool_id = cover_details?.pool_id;
+
+    // Reserve the cover before awaiting governance so a concurrent filing cannot slip in.
+    let claim_id = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let key = (caller, cover_id.clone());
+        if state.open_claims.contains_key(&key) {
+            return Err("An open claim already exists for this cover".to_string());
+        }
+        let claim_id = state.claim_count.clone() + Nat::from(1u64);
+        state.claim_count = claim_id.clone();
+        state.open_claims.insert(key, claim_id.clone());
+        Ok::<Nat, String>(claim_id)
+    })?;
+
+    let params = ProposalParam {
+        user: caller,
+        risk_type: cover.risk_type.clone(),
+        cover_id: cover_id.clone(),
+        tx_hash: tx_hash.clone(),
+        btc_address: btc_address.clone(),
+        description: description.clone(),
+        pool_id: pool_id.clone(),
+        claim_amount: amount.clone(),
+    };
+    let proposal_result: Result<(Result<Nat, String>,), _> = call(gov_canister, "createProposal", (params,)).await;
+    let proposal_id = match proposal_result {
+        Ok((Ok(proposal_id),)) => proposal_id,
+        failure => {
+            STATE.with(|state| state.borrow_mut().open_claims.remove(&(caller, cover_id.clone())));
+            return match failure {
+                Ok((Err(err),)) => Err(err),
+                _ => Err("Failed to create claim proposal".to_string()),
+            };
+        }
+    };
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.claims.insert(claim_id.clone(), Claim {
+            id: claim_id.clone(),
+            user: caller,
+            cover_id,
+            pool_id,
+            risk_type: cover.risk_type,
+            amount,
+            tx_hash,
+            btc_address,
+            description,
+            proposal_id,
+            evidence: None,
+            status: ClaimStatus::Open,
+            payouts: vec![],
+            cover_settled: false,
+            created_at: Nat::from(ic_cdk::api::time() / 1_000_000_000),
+        });
+        state.user_claims.entry(caller).or_default().push(claim_id.clone());
+    });
+
+    Ok(claim_id)
+}
+
+// Pulls the vote outcome and any verified evidence from governance into the claim record.
+#[update(name = "syncClaim")]
+pub async fn sync_claim(claim_id: Nat) -> Result<Claim, String> {
+    let (gov_canister, proposal_id) = STATE.with(|state| {
+        let state = state.borrow();
+        ensure_configured(&state)?;
+        let gov_canister = state.gov_address.ok_or("Governance canister address not set")?;
+        let claim = state.claims.get(&claim_id).ok_or("Claim not found")?;
+        Ok::<(Principal, Nat), String>((gov_canister, claim.proposal_id.clone()))
+    })?;
+
+    let proposal = get_proposal(gov_canister, proposal_id).await?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let claim = state.claims.get_mut(&claim_id).ok_or("Claim not found".to_string())?;
+        claim.evidence = proposal.evidence.clone();
+
+        // An approved claim proposal only becomes payable once governance's timelock has executed it.
+        if claim.status == ClaimStatus::Open {
+            match proposal.status {
+                ProposalStatus::Executed => claim.status = ClaimStatus::Approved,
+                ProposalStatus::Rejected | ProposalStatus::Expired | ProposalStatus::QuorumNotMet => {
+                    close_claim(&mut state, &claim_id, ClaimStatus::Rejected)?;
+                },
+                ProposalStatus::Cancelled => close_claim(&mut state, &claim_id, ClaimStatus::Cancelled)?,
+                _ => {},
+            }
+        }
+
+        state.claims.get(&claim_id).cloned().ok_or("Claim not found".to_string())
+    })
+}
+
+// Pays an approved claim and settles the cover and proposal. A claim left in Paying after a failed
+// follow-up call can be retried; it resumes at the first step not yet recorded, so neither the payout
+// nor the cover settlement is ever repeated.
+#[update(name = "payoutClaim")]
+pub async fn payout_claim(claim_id: Nat) -> Result<(), String> {
+    let _guard = PayoutGuard::acquire(&claim_id)?;
+    sync_claim(claim_id.clone()).await?;
+
+    let (mut claim, gov_canister, cover_canister, pool_canister) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let gov_canister = state.gov_address.ok_or("Governance canister address not set")?;
+        let cover_canister = state.cover_address.ok_or("Cover canister address not set")?;
+        let pool_canister = state.pool_address.ok_or("Pool canister address not set")?;
+        let claim = state.claims.get_mut(&claim_id).ok_or("Claim not found")?;
+
+        match claim.status {
+            ClaimStatus::Approved => claim.status = ClaimStatus::Paying,
+            ClaimStatus::Paying if !claim.payouts.is_empty() => {},
+            ClaimStatus::Paying => return Err("Claim payout already in progress".to_string()),
+            _ => return Err("Claim is not approved".to_string()),
+        }
+        Ok::<(Claim, Principal, Principal, Principal), String>((claim.clone(), gov_canister, cover_canister, pool_canister))
+    })?;
+
+    if payout::next_step(&claim) == payout::Step::Transfer {
+        let payout_result: Result<(Result<Nat, String>,), _> = call(
+            pool_canister,
+            "payoutClaim",
+            (claim.user, claim.pool_id.clone(), claim.amount.clone())
+        ).await;
+        let block_index = match payout_result {
+            Ok((Ok(block_index),)) => block_index,
+            failure => {
+                STATE.with(|state| {
+                    if let Some(claim) = state.borrow_mut().claims.get_mut(&claim_id) {
+                        claim.status = ClaimStatus::Approved;
+                    }
+                });
+                return match failure {
+                    Ok((Err(err),)) => Err(err),
+                    _ => Err("Failed to pay out claim".to_string()),
+                };
+            }
+        };
+
+        let payout = Payout {
+            amount: claim.amount.clone(),
+            block_index,
+            timestamp: Nat::from(ic_cdk::api::time() / 1_000_000_000),
+        };
+        claim = record_claim(&claim_id, |claim| claim.payouts.push(payout))?;
+    }
+
+    if payout::next_step(&claim) == payout::Step::SettleCover {
+        let cover_update: Result<(Result<(), String>,), _> = call(
+            cover_canister,
+            "updateUserCoverValue",
+            (claim.user, claim.cover_id.clone(), claim.amount.clone())
+        ).await;
+        cover_update.map_err(|_| "Failed to update user cover".to_string())?.0?;
+        claim = record_claim(&claim_id, |claim| claim.cover_settled = true)?;
+    }
+
+    let status_update: Result<(Result<(), String>,), _> = call(gov_canister, "updateProposalStatusToClaimed", (claim.proposal_id.clone(),)).await;
+    status_update.map_err(|_| "Failed to update proposal status".to_string())?.0?;
+
+    STATE.with(|state| close_claim(&mut state.borrow_mut(), &claim_id, ClaimStatus::Paid))
+}
+
+#[query(name = "getClaim")]
+pub fn get_claim(claim_id: Nat) -> Result<Claim, String> {
+    STATE.with(|state| state.borrow().claims.get(&claim_id).cloned().ok_or("Claim not found".to_string()))
+}
+
+#[query(name = "getUserClaims")]
+pub fn get_user_claims(user: Principal) -> Vec<Claim> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state.user_claims
+            .get(&user)
+            .map(|ids| ids.iter().filter_map(|id| state.claims.get(id).cloned()).collect())
+            .unwrap_or_default()
+    })
+}
+
+#[query(name = "getOpenClaim")]
+pub fn get_open_claim(user: Principal, cover_id: Nat) -> Option<Claim> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state.open_claims.get(&(user, cover_id)).and_then(|id| state.claims.get(id).cloned())
+    })
+}
+
+#[update(name = "setCanisterIds")]
+pub fn set_canister_ids(ids: CanisterIds) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) {
+            return Err("Only the owner can set canister ids".to_string());
+        }
+        state.gov_address = ids.governance.or(state.gov_address);
+        state.cover_address = ids.cover.or(state.cover_address);
+        state.pool_address = ids.pool.or(state.pool_address);
+        Ok(())
+    })
+}
+
+#[query(name = "getCanisterIds")]
+pub fn get_canister_ids() -> CanisterIds {
+    STATE.with(|state| {
+        let state = state.borrow();
+        CanisterIds {
+            governance: state.gov_address,
+            cover: state.cover_address,
+            pool: state.pool_address,
+        }
+    })
+}
+
+async fn sync_registry() -> Result<u64, String> {
+    let registry = STATE.with(|state| state.borrow().registry.ok_or("Registry canister not set".to_string()))?;
+    let result: Result<(ProtocolCanisters,), _> = call(registry, "getCanisters", ()).await;
+    let (canisters,) = result.map_err(|_| "Failed to read the registry".to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if canisters.version > state.registry_version {
+            state.gov_address = canisters.governance.or(state.gov_address);
+            state.cover_address = canisters.cover.or(state.cover_address);
+            state.pool_address = canisters.pool.or(state.pool_address);
+            state.registry_version = canisters.version;
+        }
+        Ok(state.registry_version)
+    })
+}
+
+fn schedule_registry_sync() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(async {
+        let _ = sync_registry().await;
+    }));
+}
+
+#[update(name = "setRegistry")]
+pub fn set_registry(registry: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) {
+            return Err("Only the owner can set the registry".to_string());
+        }
+        state.registry = Some(registry);
+        state.registry_version = 0;
+        Ok(())
+    })?;
+    schedule_registry_sync();
+    Ok(())
+}
+
+#[update(name = "syncRegistry")]
+pub async fn sync_registry_now() -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if STATE.with(|state| state.borrow().owner != Some(caller)) {
+        return Err("Only the owner can sync the registry".to_string());
+    }
+    sync_registry().await
+}
+
+#[query(name = "getRegistry")]
+pub fn get_registry() -> (Option<Principal>, u64) {
+    STATE.with(|state| {
+        let state = state.borrow();
+        (state.registry, state.registry_version)
+    })
+}
+
+#[query(name = "getOwner")]
+fn get_owner() -> Option<Principal> {
+    STATE.with(|state| state.borrow().owner)
+}
+
+ic_cdk::export_candid!();
@@ -0,0 +1,51 @@
+use crate::types::Claim;
+
+// The calls that settle an approved claim, in the order they run. Each one is recorded on the claim as
+// soon as it succeeds, so a retry after a failed call resumes there instead of repeating earlier calls.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    Transfer,
+    SettleCover,
+    MarkClaimed,
+}
+
+pub fn next_step(claim: &Claim) -> Step {
+    if claim.payouts.is_empty() {
+        Step::Transfer
+    } else if !claim.cover_settled {
+        Step::SettleCover
+    } else {
+        Step::MarkClaimed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{claim, nat};
+    use crate::types::Payout;
+
+    fn paid(claim: &mut Claim) {
+        claim.payouts.push(Payout { amount: claim.amount.clone(), block_index: nat(7), timestamp: nat(0) });
+    }
+
+    #[test]
+    fn unpaid_claim_starts_with_the_transfer() {
+        assert_eq!(next_step(&claim()), Step::Transfer);
+    }
+
+    #[test]
+    fn retry_after_failed_cover_update_settles_the_cover_without_paying_again() {
+        let mut claim = claim();
+        paid(&mut claim);
+        assert_eq!(next_step(&claim), Step::SettleCover);
+    }
+
+    #[test]
+    fn retry_after_failed_proposal_update_does_not_settle_the_cover_again() {
+        let mut claim = claim();
+        paid(&mut claim);
+        claim.cover_settled = true;
+        assert_eq!(next_step(&claim), Step::MarkClaimed);
+    }
+}
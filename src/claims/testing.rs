@@ -0,0 +1,29 @@
+// Fixtures shared by the claims canister's unit tests.
+use candid::{Nat, Principal};
+
+use crate::types::{Claim, ClaimStatus, RiskType};
+
+pub fn nat(value: u64) -> Nat {
+    Nat::from(value)
+}
+
+// An approved claim for 100 bqBTC out of pool 1 that has not been paid yet.
+pub fn claim() -> Claim {
+    Claim {
+        id: nat(1),
+        user: Principal::anonymous(),
+        cover_id: nat(1),
+        pool_id: nat(1),
+        risk_type: RiskType::Slashing,
+        amount: nat(100),
+        tx_hash: String::new(),
+        btc_address: String::new(),
+        description: String::new(),
+        proposal_id: nat(1),
+        evidence: None,
+        status: ClaimStatus::Approved,
+        payouts: vec![],
+        cover_settled: false,
+        created_at: nat(0),
+    }
+}
@@ -2,12 +2,26 @@ use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_cdk_macros::*;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::time::Duration;
 use ic_cdk::api::call::call;
+use ic_stable_structures::StableBTreeMap;
+use shared::stable::{self, Candid, Memory};
+mod pricing;
+#[cfg(test)]
+mod testing;
 mod types;
-use types::{Cover, Deposit, GenericCoverInfo, Pool, RiskType, Status};
+use types::{Cover, GenericCoverInfo, Pool, PricingCurve, ProtocolCanisters, RiskType, TxReceipt};
+
+const BASIS_POINTS: u64 = 10_000;
+const USER_COVERS_MEMORY: u8 = 1;
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::default();
+
+    // One entry per policy sold, keyed by holder and cover, so policies live in stable memory
+    // instead of being copied in and out of the heap on each upgrade.
+    static USER_COVERS: RefCell<StableBTreeMap<Candid<(Principal, Nat)>, Candid<GenericCoverInfo>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(USER_COVERS_MEMORY)));
 }
 
 #[derive(CandidType, Deserialize, Default)]
@@ -18,11 +32,81 @@ struct State {
     bqbtc_address: Option<Principal>,
     lp_contract: Option<Principal>,
     gov_address: Option<Principal>,
+    claims_address: Option<Principal>,
     participants: Vec<Principal>,
     participation: HashMap<Principal, Nat>,
-    user_covers: HashMap<Principal, HashMap<Nat, GenericCoverInfo>>,
-    lp_claims: HashMap<Principal, HashMap<Nat, Nat>>,
-    cover_ids: Vec<Nat>
+    lp_claims: HashMap<Principal, HashMap<(Nat, Nat), Nat>>,
+    cover_ids: Vec<Nat>,
+    registry: Option<Principal>,
+    registry_version: u64,
+    treasury: Option<Principal>,
+    protocol_fee_bps: u64,
+    pricing_curves: HashMap<Nat, PricingCurve>,
+    // Premium owed to each pool that has not reached it yet: `unsent` is still held here, while `unbooked`
+    // was transferred to the pool canister but not credited to the pool.
+    unsent_premiums: HashMap<Nat, Nat>,
+    unbooked_premiums: HashMap<Nat, Nat>,
+}
+
+// Layouts the state has been saved in across upgrades; post_upgrade migrates older ones forward.
+#[derive(CandidType, Deserialize)]
+enum StableState {
+    V1(State),
+}
+
+fn find_user_cover(user: Principal, cover_id: &Nat) -> Option<GenericCoverInfo> {
+    USER_COVERS.with(|covers| covers.borrow().get(&Candid((user, cover_id.clone())))).map(|Candid(user_cover)| user_cover)
+}
+
+fn save_user_cover(user_cover: GenericCoverInfo) {
+    USER_COVERS.with(|covers| {
+        covers.borrow_mut().insert(Candid((user_cover.user, user_cover.cover_id.clone())), Candid(user_cover))
+    });
+}
+
+fn user_covers_of(user: Principal) -> Vec<GenericCoverInfo> {
+    USER_COVERS.with(|covers| {
+        covers.borrow()
+            .range(Candid((user, Nat::from(0u64)))..)
+            .take_while(|(Candid((holder, _)), _)| *holder == user)
+            .map(|(_, Candid(user_cover))| user_cover)
+            .collect()
+    })
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UnroutedPremium {
+    pool_id: Nat,
+    unsent: Nat,
+    unbooked: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CanisterIds {
+    pool: Option<Principal>,
+    governance: Option<Principal>,
+    bqbtc: Option<Principal>,
+    claims: Option<Principal>,
+}
+
+// User-facing endpoints refuse to run until every peer canister has been wired up.
+fn ensure_configured(state: &State) -> Result<(), String> {
+    let missing: Vec<&str> = [
+        ("pool", state.lp_contract),
+        ("governance", state.gov_address),
+        ("bqBTC", state.bqbtc_address),
+        ("claims", state.claims_address),
+    ]
+    .iter()
+    .filter(|(_, id)| id.is_none())
+    .map(|(name, _)| *name)
+    .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Cover canister is not configured: missing {}", missing.join(", ")))
+    }
 }
 
 #[init]
@@ -36,6 +120,26 @@ fn init(lp_contract: Principal, initial_owner: Principal, governance: Principal,
     });
 }
 
+#[pre_upgrade]
+fn pre_upgrade() {
+    STATE.with(|state| stable::save_state(&StableState::V1(std::mem::take(&mut *state.borrow_mut()))));
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let saved = match stable::restore_state() {
+        Some(StableState::V1(saved)) => saved,
+        None => {
+            ic_cdk::println!("No saved state found, starting from an empty state");
+            State::default()
+        },
+    };
+    STATE.with(|state| *state.borrow_mut() = saved);
+    if STATE.with(|state| state.borrow().registry.is_some()) {
+        schedule_registry_sync();
+    }
+}
+
 #[update(name = "createCover")]
 pub async fn create_cover(
     cover_id: Nat,
@@ -50,45 +154,42 @@ pub async fn create_cover(
     let caller =  ic_cdk::caller();
     let pool_contract = STATE.with(| state | {
         let state = state.borrow();
-        state.lp_contract.unwrap()
-    });
-
-    let pool_covers: Result<(Vec<Cover>,), _> = call(pool_contract, "getPoolCovers", (caller, pool_id.clone())).await;
-    let (covers,) = pool_covers.map_err(|_| "Failed to get pool covers")?;
-    let pool_details: Result<(Pool,), _> = call(pool_contract, "getPool", (caller, pool_id.clone())).await;
-    let (pool, ) = pool_details.map_err(|_| "Failed to get pool")?;
-
-    for cover in covers.iter() {
-        if cover.cover_name == cover_name || cover_id.clone() == cover.id {
+        ensure_configured(&state)?;
+        if state.owner != Some(caller) && state.gov_address != Some(caller) {
+            return Err("Only owner or governance can manage covers".to_string());
+        }
+        if state.covers.contains_key(&cover_id) {
             return Err("Cover Already exists!".to_string());
         }
-    }
-
-    if risk_type != pool.risk_type {
-        return Err("Wrong pool, risk type must be the same!".to_string());
-    }
+        state.lp_contract.ok_or("LP contract address not set".to_string())
+    })?;
+    validate_capacity(&capacity)?;
+
+    let mut cover = Cover {
+        id: cover_id.clone(),
+        cover_name,
+        risk_type,
+        chains,
+        capacity,
+        cost,
+        capacity_amount: Nat::from(0u64),
+        cover_values: Nat::from(0u64),
+        max_amount: Nat::from(0u64),
+        pool_id: pool_id.clone(),
+        cid,
+    };
 
-    let precision = Nat::from(1_000_000_000_000_000_000u128);
-    let maxamount: Nat = (pool.tvl * (capacity.clone() * precision.clone() / Nat::from(100u64))) / precision;
+    // The pool checks the risk type and reserves the capacity from its percentage split in one step.
+    let reserve_result: Result<(Result<Nat, String>,), _> = call(pool_contract, "addPoolCover", (pool_id, cover.clone())).await;
+    let (tvl,) = reserve_result.map_err(|_| "Failed to reserve cover capacity in the pool")?;
+    let tvl = tvl?;
 
+    let maxamount = capacity_amount(&tvl, &cover.capacity);
+    cover.capacity_amount = maxamount.clone();
+    cover.max_amount = maxamount;
 
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-              
-        let cover = Cover {
-            id: cover_id.clone(),
-            cover_name,
-            risk_type,
-            chains,
-            capacity,
-            cost,
-            capacity_amount: maxamount.clone(),
-            cover_values: Nat::from(0u64),
-            max_amount: maxamount,
-            pool_id,
-            cid,
-        };
-
         state.covers.insert(cover_id.clone(), cover);
         state.cover_ids.push(cover_id);
         Ok(())
@@ -107,100 +208,173 @@ pub async fn update_cover(
     pool_id: Nat
 ) -> Result<(), String> {
     let caller =  ic_cdk::caller();
-    let pool_contract = STATE.with(| state | {
+    let (pool_contract, mut cover) = STATE.with(| state | {
         let state = state.borrow();
-        state.lp_contract.unwrap()
-    });
-
-    let pool_covers: Result<(Vec<Cover>,), _> = call(pool_contract, "getPoolCovers", (caller, pool_id.clone())).await;
-    let (covers,) = pool_covers.map_err(|_| "Failed to get pool covers")?;
-    let pool_details: Result<(Pool,), _> = call(pool_contract, "getPool", (caller, pool_id.clone())).await;
-    let (pool, ) = pool_details.map_err(|_| "Failed to get pool")?;
-
-    for cover in covers.iter() {
-        if cover.cover_name == cover_name || cover_id.clone() != cover.id {
-            return Err("Cover Already exists!".to_string());
+        ensure_configured(&state)?;
+        if state.owner != Some(caller) && state.gov_address != Some(caller) {
+            return Err("Only owner or governance can manage covers".to_string());
         }
+        let pool_contract = state.lp_contract.ok_or("LP contract address not set".to_string())?;
+        let cover = state.covers.get(&cover_id).ok_or("Cover not found")?;
+        Ok::<(Principal, Cover), String>((pool_contract, cover.clone()))
+    })?;
+    validate_capacity(&capacity)?;
+    if cover.pool_id != pool_id {
+        return Err("Cover cannot move to another pool".to_string());
     }
 
-    if risk_type != pool.risk_type {
-        return Err("Wrong pool, risk type must be the same!".to_string());
-    }
+    cover.cover_name = cover_name;
+    cover.risk_type = risk_type;
+    cover.chains = chains;
+    cover.capacity = capacity;
+    cover.cost = cost;
+    cover.cid = cid;
 
-    let precision = Nat::from(1_000_000_000_000_000_000u128);
-    let maxamount: Nat = (pool.tvl * (capacity.clone() * precision.clone() / Nat::from(100u64))) / precision;
+    // The pool adjusts the reservation by the change in capacity and refuses to shrink below what is sold.
+    let resize_result: Result<(Result<Nat, String>,), _> = call(pool_contract, "updatePoolCovers", (pool_id, cover.clone())).await;
+    let (tvl,) = resize_result.map_err(|_| "Failed to resize cover capacity in the pool")?;
+    let tvl = tvl?;
+    let maxamount = capacity_amount(&tvl, &cover.capacity);
 
-    let old_capacity = STATE.with(|state| {
+    STATE.with(|state| {
         let mut state = state.borrow_mut();
+        let stored = state.covers.get_mut(&cover_id).ok_or("Cover not found")?;
+        stored.cover_name = cover.cover_name;
+        stored.risk_type = cover.risk_type;
+        stored.chains = cover.chains;
+        stored.capacity = cover.capacity;
+        stored.cost = cover.cost;
+        stored.cid = cover.cid;
+        stored.capacity_amount = maxamount.clone();
+        stored.max_amount = if maxamount > stored.cover_values {
+            maxamount - stored.cover_values.clone()
+        } else {
+            Nat::from(0u64)
+        };
+        Ok(())
+    })
+}
+
+// Withdraws a cover from sale and hands its capacity back to the pool. Covers still backing
+// unexpired policies cannot be retired.
+#[update(name = "retireCover")]
+pub async fn retire_cover(cover_id: Nat) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let (pool_contract, pool_id, max_amount) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) && state.gov_address != Some(caller) {
+            return Err("Only owner or governance can manage covers".to_string());
+        }
+        let pool_contract = state.lp_contract.ok_or("LP contract address not set".to_string())?;
 
+        let now = Nat::from(ic_cdk::api::time() / 1_000_000_000);
+        let has_active_policies = USER_COVERS.with(|covers| {
+            covers.borrow().iter().any(|(Candid((_, id)), Candid(user_cover))| {
+                id == cover_id && user_cover.is_active && user_cover.end_day > now && user_cover.cover_value > Nat::from(0u64)
+            })
+        });
+        if has_active_policies {
+            return Err("Cover still backs active policies".to_string());
+        }
+
+        // Sales stop before the capacity is released so nothing is sold against it in the meantime.
         let cover = state.covers.get_mut(&cover_id).ok_or("Cover not found")?;
-        let old_capacity = cover.capacity.clone();
+        let max_amount = std::mem::replace(&mut cover.max_amount, Nat::from(0u64));
+        Ok::<(Principal, Nat, Nat), String>((pool_contract, cover.pool_id.clone(), max_amount))
+    })?;
 
+    let release_result: Result<(Result<(), String>,), _> = call(pool_contract, "releasePoolCover", (pool_id, cover_id.clone())).await;
+    let released = match release_result {
+        Ok((Ok(()),)) => Ok(()),
+        Ok((Err(err),)) => Err(err),
+        Err(_) => Err("Failed to release cover capacity in the pool".to_string()),
+    };
+    if let Err(err) = released {
+        STATE.with(|state| {
+            if let Some(cover) = state.borrow_mut().covers.get_mut(&cover_id) {
+                cover.max_amount = max_amount;
+            }
+        });
+        return Err(err);
+    }
 
-        if cover.cover_values > maxamount {
-            return Err("Wrong Pool".to_string());
-        }
-        
-        cover.cover_name = cover_name;
-        cover.risk_type = risk_type;
-        cover.chains = chains;
-        cover.capacity = capacity.clone();
-        cover.cost = cost;
-        cover.cid = cid;
-        cover.capacity_amount = capacity.clone();
-        
-        Ok::<Nat, String>(old_capacity)
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.covers.remove(&cover_id);
+        state.cover_ids.retain(|id| *id != cover_id);
+        state.pricing_curves.remove(&cover_id);
     });
-    let old_cover_cap = old_capacity.unwrap();
-    let difference = if old_cover_cap.clone() > capacity.clone() {
-        old_cover_cap.clone() - capacity.clone()
-    } else {
-        capacity.clone() - old_cover_cap.clone()
-    };
-    
-    let _: Result<(), String> = if old_cover_cap > capacity {
-        call(pool_contract, "increasePercentageSplit", (pool_id.clone(), difference.clone())).await
-            .map_err(|_| "Error increasing percentage split".to_string())
-    } else {
-        call(pool_contract, "reducePercentageSplit", (pool_id.clone(), difference.clone())).await
-            .map_err(|_| "Error decreasing percentage split".to_string())
-    };
+    Ok(())
+}
 
+fn validate_capacity(capacity: &Nat) -> Result<(), String> {
+    if *capacity == Nat::from(0u64) || *capacity > Nat::from(100u64) {
+        return Err("Cover capacity must be between 1 and 100 percent of the pool".to_string());
+    }
     Ok(())
 }
 
+fn capacity_amount(tvl: &Nat, capacity: &Nat) -> Nat {
+    let precision = Nat::from(1_000_000_000_000_000_000u128);
+    (tvl.clone() * (capacity.clone() * precision.clone() / Nat::from(100u64))) / precision
+}
+
+// `max_fee` is the most the buyer will pay; the premium charged is always the current quote.
 #[update(name = "purchaseCover")]
-pub async fn purchase_cover(cover_id: Nat, cover_value: Nat, cover_period: Nat, cover_fee: Nat) -> Result<(), String> {
+pub async fn purchase_cover(cover_id: Nat, cover_value: Nat, cover_period: Nat, max_fee: Nat) -> Result<(), String> {
     let caller = ic_cdk::caller();
     let bqbtc_address = STATE.with(| state | {
         let state = state.borrow();
-        let bqbtc_address = state.bqbtc_address.unwrap();
-
-        bqbtc_address
-    });
-
+        ensure_configured(&state)?;
+        state.bqbtc_address.ok_or("bqBTC canister address not set".to_string())
+    })?;
 
-    let burn_result: Result<(), _> = call(bqbtc_address, "burn", (caller, cover_fee.clone())).await;
-    burn_result.map_err(|e| format!("Error burning tokens: {:?}", e))?;
+    if cover_period == Nat::from(0u64) {
+        return Err("Cover period must be greater than zero".to_string());
+    }
 
-    let cover = STATE.with(|state| {
+    // Capacity is reserved before the fee is taken so a sold-out cover never keeps a premium.
+    let (cover, rate_bps, quote) = STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let cover = state.covers.get_mut(&cover_id).ok_or("Cover not found")?;
-
+        let cover = state.covers.get(&cover_id).ok_or("Cover not found")?;
         if cover_value > cover.max_amount {
             return Err("Insufficient capacity".to_string());
         }
+        let rate_bps = current_rate(&state, cover, &cover_value);
+        let quote = pricing::premium(&cover_value, &cover_period, &rate_bps);
+        if quote == Nat::from(0u64) {
+            return Err("Cover must carry a non-zero premium".to_string());
+        }
+        if quote > max_fee {
+            return Err(format!("Quoted premium {} exceeds the maximum fee {}", quote, max_fee));
+        }
 
+        let cover = state.covers.get_mut(&cover_id).ok_or("Cover not found")?;
         cover.cover_values += cover_value.clone();
         cover.max_amount -= cover_value.clone();
-        Ok(cover.clone())
+        Ok((cover.clone(), rate_bps, quote))
     })?;
 
+    let transfer_result: Result<(TxReceipt,), _> = call(bqbtc_address, "transfer_from", (caller, ic_cdk::id(), quote.clone())).await;
+    let transferred = match transfer_result {
+        Ok((Ok(_),)) => Ok(()),
+        Ok((Err(e),)) => Err(format!("Error transferring cover fee: {:?}", e)),
+        Err(e) => Err(format!("Error transferring cover fee: {:?}", e)),
+    };
+    if let Err(err) = transferred {
+        STATE.with(|state| {
+            if let Some(cover) = state.borrow_mut().covers.get_mut(&cover_id) {
+                cover.cover_values -= cover_value.clone();
+                cover.max_amount += cover_value.clone();
+            }
+        });
+        return Err(err);
+    }
+
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let user_cover_map = state.user_covers.entry(caller).or_insert_with(HashMap::new);
 
-        user_cover_map.insert(cover_id.clone(), GenericCoverInfo {
+        save_user_cover(GenericCoverInfo {
             user: caller,
             cover_id,
             risk_type: cover.risk_type.clone(),
@@ -210,72 +384,368 @@ pub async fn purchase_cover(cover_id: Nat, cover_value: Nat, cover_period: Nat,
             cover_period: cover_period.clone(),
             end_day: Nat::from(ic_cdk::api::time() / 1_000_000_000) + cover_period * Nat::from(86400u64),
             is_active: true,
+            premium_rate_bps: rate_bps,
+            premium_paid: quote.clone(),
         });
 
         if !state.participants.contains(&caller) {
             state.participants.push(caller);
         }
         *state.participation.entry(caller).or_insert(Nat::from(0u64)) += Nat::from(1u64);
+    });
+
+    // The cover is already in force, so a premium that cannot be forwarded is recorded for retryPremiums.
+    if let Err(err) = route_premium(bqbtc_address, cover.pool_id, quote).await {
+        ic_cdk::println!("Failed to route premium for cover id {}: {}", cover.id, err);
+    }
+
+    Ok(())
+}
+
+// Sends the protocol fee to the treasury and the rest of a premium to the pool backing the cover.
+async fn route_premium(bqbtc_address: Principal, pool_id: Nat, premium: Nat) -> Result<(), String> {
+    let (lp_contract, treasury, protocol_fee_bps) = STATE.with(|state| {
+        let state = state.borrow();
+        (state.lp_contract, state.treasury, state.protocol_fee_bps)
+    });
+    let Some(lp_contract) = lp_contract else {
+        owe_premium(|state| &mut state.unsent_premiums, pool_id, premium);
+        return Err("LP contract address not set".to_string());
+    };
+
+    let mut lp_share = premium.clone();
+    if let Some(treasury) = treasury {
+        let protocol_fee = premium * Nat::from(protocol_fee_bps) / Nat::from(BASIS_POINTS);
+        if protocol_fee > Nat::from(0u64) {
+            let fee_result: Result<(TxReceipt,), _> = call(bqbtc_address, "transfer", (treasury, protocol_fee.clone())).await;
+            match fee_result {
+                Ok((Ok(_),)) => lp_share -= protocol_fee,
+                _ => ic_cdk::println!("Failed to transfer protocol fee to the treasury"),
+            }
+        }
+    }
+    if lp_share == Nat::from(0u64) {
+        return Ok(());
+    }
+
+    forward_premium(bqbtc_address, lp_contract, pool_id, lp_share).await
+}
+
+fn owe_premium(owed: impl FnOnce(&mut State) -> &mut HashMap<Nat, Nat>, pool_id: Nat, amount: Nat) {
+    STATE.with(|state| *owed(&mut state.borrow_mut()).entry(pool_id).or_insert(Nat::from(0u64)) += amount);
+}
+
+// Transfers `amount` to the pool canister and books it for `pool_id`, recording whichever step failed.
+async fn forward_premium(bqbtc_address: Principal, lp_contract: Principal, pool_id: Nat, amount: Nat) -> Result<(), String> {
+    let transfer_result: Result<(TxReceipt,), _> = call(bqbtc_address, "transfer", (lp_contract, amount.clone())).await;
+    if !matches!(transfer_result, Ok((Ok(_),))) {
+        owe_premium(|state| &mut state.unsent_premiums, pool_id, amount);
+        return Err("Error transferring premium".to_string());
+    }
+    book_premium(lp_contract, pool_id, amount).await
+}
+
+async fn book_premium(lp_contract: Principal, pool_id: Nat, amount: Nat) -> Result<(), String> {
+    let premium_result: Result<(Result<(), String>,), _> = call(lp_contract, "depositPremium", (pool_id.clone(), amount.clone())).await;
+    match premium_result {
+        Ok((Ok(()),)) => Ok(()),
+        _ => {
+            owe_premium(|state| &mut state.unbooked_premiums, pool_id, amount);
+            Err("Failed to book premium in the pool".to_string())
+        },
+    }
+}
+
+// Works off premiums that earlier purchases could not forward. Each is taken off the books before its calls
+// and recorded again if they fail, so a concurrent retry never forwards the same premium twice.
+#[update(name = "retryPremiums")]
+pub async fn retry_premiums() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let (bqbtc_address, lp_contract, unsent, unbooked) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) && state.gov_address != Some(caller) {
+            return Err("Not authorized".to_string());
+        }
+        let bqbtc_address = state.bqbtc_address.ok_or("bqBTC canister address not set".to_string())?;
+        let lp_contract = state.lp_contract.ok_or("LP contract address not set".to_string())?;
+        let unsent = std::mem::take(&mut state.unsent_premiums);
+        let unbooked = std::mem::take(&mut state.unbooked_premiums);
+        Ok((bqbtc_address, lp_contract, unsent, unbooked))
+    })?;
+
+    let mut failures = 0;
+    for (pool_id, amount) in unbooked {
+        if book_premium(lp_contract, pool_id, amount).await.is_err() {
+            failures += 1;
+        }
+    }
+    for (pool_id, amount) in unsent {
+        if forward_premium(bqbtc_address, lp_contract, pool_id, amount).await.is_err() {
+            failures += 1;
+        }
+    }
+
+    match failures {
+        0 => Ok(()),
+        failures => Err(format!("{} premiums could not be forwarded and remain owed", failures)),
+    }
+}
+
+#[query(name = "getUnroutedPremiums")]
+pub fn get_unrouted_premiums() -> Vec<UnroutedPremium> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let mut pool_ids: Vec<&Nat> = state.unsent_premiums.keys().chain(state.unbooked_premiums.keys()).collect();
+        pool_ids.sort();
+        pool_ids.dedup();
+        pool_ids
+            .into_iter()
+            .map(|pool_id| UnroutedPremium {
+                pool_id: pool_id.clone(),
+                unsent: state.unsent_premiums.get(pool_id).cloned().unwrap_or_else(|| Nat::from(0u64)),
+                unbooked: state.unbooked_premiums.get(pool_id).cloned().unwrap_or_else(|| Nat::from(0u64)),
+            })
+            .collect()
+    })
+}
+
+// Annual rate in basis points for selling `cover_value` more of `cover`. Utilization is the greater of the
+// cover's own sold share of capacity and the sold share across every cover drawing on the same pool.
+fn current_rate(state: &State, cover: &Cover, cover_value: &Nat) -> Nat {
+    let curve = state.pricing_curves.get(&cover.id).cloned().unwrap_or_else(|| pricing::default_curve(cover));
+
+    let cover_utilization = pricing::utilization_bps(&(cover.cover_values.clone() + cover_value.clone()), &cover.capacity_amount);
+    let (pool_used, pool_capacity) = state.covers
+        .values()
+        .filter(|other| other.pool_id == cover.pool_id)
+        .fold((cover_value.clone(), Nat::from(0u64)), |(used, capacity), other| {
+            (used + other.cover_values.clone(), capacity + other.capacity_amount.clone())
+        });
+    let pool_utilization = pricing::utilization_bps(&pool_used, &pool_capacity);
+
+    pricing::rate_bps(&curve, &cover_utilization.max(pool_utilization))
+}
+
+#[query(name = "quoteCover")]
+pub fn quote_cover(cover_id: Nat, cover_value: Nat, cover_period: Nat) -> Result<Nat, String> {
+    if cover_period == Nat::from(0u64) {
+        return Err("Cover period must be greater than zero".to_string());
+    }
+    STATE.with(|state| {
+        let state = state.borrow();
+        let cover = state.covers.get(&cover_id).ok_or("Cover not found")?;
+        if cover_value > cover.max_amount {
+            return Err("Insufficient capacity".to_string());
+        }
+        let rate_bps = current_rate(&state, cover, &cover_value);
+        let quote = pricing::premium(&cover_value, &cover_period, &rate_bps);
+        if quote == Nat::from(0u64) {
+            return Err("Cover must carry a non-zero premium".to_string());
+        }
+        Ok(quote)
+    })
+}
 
+#[query(name = "getCurrentRate")]
+pub fn get_current_rate(cover_id: Nat) -> Result<Nat, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let cover = state.covers.get(&cover_id).ok_or("Cover not found")?;
+        Ok(current_rate(&state, cover, &Nat::from(0u64)))
+    })
+}
+
+#[update(name = "setPricingCurve")]
+pub fn set_pricing_curve(cover_id: Nat, curve: PricingCurve) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    pricing::validate(&curve)?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) && state.gov_address != Some(caller) {
+            return Err("Only owner or governance can set pricing curves".to_string());
+        }
+        if !state.covers.contains_key(&cover_id) {
+            return Err("Cover not found".to_string());
+        }
+        state.pricing_curves.insert(cover_id, curve);
         Ok(())
     })
 }
 
+#[query(name = "getPricingCurve")]
+pub fn get_pricing_curve(cover_id: Nat) -> Result<PricingCurve, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let cover = state.covers.get(&cover_id).ok_or("Cover not found")?;
+        Ok(state.pricing_curves.get(&cover_id).cloned().unwrap_or_else(|| pricing::default_curve(cover)))
+    })
+}
+
+#[update(name = "setProtocolFee")]
+pub fn set_protocol_fee(treasury: Option<Principal>, protocol_fee_bps: u64) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if protocol_fee_bps > BASIS_POINTS {
+        return Err("Protocol fee must be expressed in basis points".to_string());
+    }
+    if treasury.is_none() && protocol_fee_bps > 0 {
+        return Err("A treasury is required to charge a protocol fee".to_string());
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) {
+            return Err("Only the owner can set the protocol fee".to_string());
+        }
+        state.treasury = treasury;
+        state.protocol_fee_bps = protocol_fee_bps;
+        Ok(())
+    })
+}
+
+#[query(name = "getProtocolFee")]
+pub fn get_protocol_fee() -> (Option<Principal>, u64) {
+    STATE.with(|state| {
+        let state = state.borrow();
+        (state.treasury, state.protocol_fee_bps)
+    })
+}
+
 #[update(name = "updateUserCoverValue")]
 pub async fn update_user_cover_value(user: Principal, cover_id: Nat, claim_paid: Nat) -> Result<(), String>{
+    let caller = ic_cdk::caller();
     STATE.with(| state | {
         let mut state = state.borrow_mut();
-        let user_cover = state.user_covers.get_mut(&user).ok_or("error getting cover").unwrap().get_mut(&cover_id).ok_or("error getting cover info").unwrap();
+        if state.claims_address != Some(caller) {
+            return Err("Only the claims canister can update cover value".to_string());
+        }
+        let mut user_cover = find_user_cover(user, &cover_id).ok_or("error getting cover info")?;
+        if user_cover.cover_value < claim_paid {
+            return Err("Claim exceeds cover value".to_string());
+        }
         user_cover.cover_value -= claim_paid.clone();
-        user_cover.claim_paid += claim_paid;
+        user_cover.claim_paid += claim_paid.clone();
+        save_user_cover(user_cover);
+        if let Some(cover) = state.covers.get_mut(&cover_id) {
+            release_exposure(cover, &claim_paid);
+        }
         Ok(())
     })
 }
 
-#[update(name = "claimPayoutForLP")]
-pub async fn claim_payout_for_lp(pool_id: Nat) -> Result<(), String> {
+// Takes a paid or expired policy's value off what the cover has sold, so the pool's capital behind it can
+// be withdrawn. The cover's sellable amount is recomputed by `updateMaxAmount` once the pool's TVL settles.
+fn release_exposure(cover: &mut Cover, value: &Nat) {
+    cover.cover_values = if cover.cover_values > *value {
+        cover.cover_values.clone() - value.clone()
+    } else {
+        Nat::from(0u64)
+    };
+}
+
+#[update(name = "setCanisterIds")]
+pub fn set_canister_ids(ids: CanisterIds) -> Result<(), String> {
     let caller = ic_cdk::caller();
-    let (lp_contract, bqbtc_address) = STATE.with(|state| {
-        let lp_contract = state.borrow().lp_contract.ok_or("LP contract address not set".to_string()).unwrap();
-        let bqbtc_address= state.borrow().bqbtc_address.ok_or("bqBTC canister address not set".to_string()).unwrap();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) {
+            return Err("Only the owner can set canister ids".to_string());
+        }
+        state.lp_contract = ids.pool.or(state.lp_contract);
+        state.gov_address = ids.governance.or(state.gov_address);
+        state.bqbtc_address = ids.bqbtc.or(state.bqbtc_address);
+        state.claims_address = ids.claims.or(state.claims_address);
+        Ok(())
+    })
+}
 
-        (lp_contract, bqbtc_address)
-    });
+#[query(name = "getCanisterIds")]
+pub fn get_canister_ids() -> CanisterIds {
+    STATE.with(|state| {
+        let state = state.borrow();
+        CanisterIds {
+            pool: state.lp_contract,
+            governance: state.gov_address,
+            bqbtc: state.bqbtc_address,
+            claims: state.claims_address,
+        }
+    })
+}
 
-    let deposit_info_result: Result<(Deposit,), _> = call(lp_contract, "getUserDeposit", (pool_id.clone(), caller)).await;
-    let (deposit_info,) = deposit_info_result.map_err(|_| "Failed to get user deposit information")?;
+async fn sync_registry() -> Result<u64, String> {
+    let registry = STATE.with(|state| state.borrow().registry.ok_or("Registry canister not set".to_string()))?;
+    let result: Result<(ProtocolCanisters,), _> = call(registry, "getCanisters", ()).await;
+    let (canisters,) = result.map_err(|_| "Failed to read the registry".to_string())?;
 
-    if deposit_info.status != Status::Active {
-        return Err("Deposit is not active".to_string());
-    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if canisters.version > state.registry_version {
+            state.lp_contract = canisters.pool.or(state.lp_contract);
+            state.gov_address = canisters.governance.or(state.gov_address);
+            state.bqbtc_address = canisters.bqbtc.or(state.bqbtc_address);
+            state.claims_address = canisters.claims.or(state.claims_address);
+            state.registry_version = canisters.version;
+        }
+        Ok(state.registry_version)
+    })
+}
 
-    let last_claim_time = STATE.with(|state| {
-        let state = state.borrow();
-        state.lp_claims.get(&caller)
-            .and_then(|claims| claims.get(&pool_id).cloned())
-            .unwrap_or(deposit_info.start_date.clone())
-    });
+fn schedule_registry_sync() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(async {
+        let _ = sync_registry().await;
+    }));
+}
 
-    let mut current_time = Nat::from(ic_cdk::api::time() / 1_000_000_000);
-    if current_time > deposit_info.expiry_date {
-        current_time = deposit_info.expiry_date;
-    }
-    let claimable_days = (current_time.clone() - last_claim_time.clone()) / Nat::from(86400u64); 
-    
-    if claimable_days <= Nat::from(0u64) {
-        return Err("No claimable reward".to_string());
+#[update(name = "setRegistry")]
+pub fn set_registry(registry: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) {
+            return Err("Only the owner can set the registry".to_string());
+        }
+        state.registry = Some(registry);
+        state.registry_version = 0;
+        Ok(())
+    })?;
+    schedule_registry_sync();
+    Ok(())
+}
+
+#[update(name = "syncRegistry")]
+pub async fn sync_registry_now() -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if STATE.with(|state| state.borrow().owner != Some(caller)) {
+        return Err("Only the owner can sync the registry".to_string());
     }
+    sync_registry().await
+}
 
-    let claimable_amount = deposit_info.daily_payout.clone() * claimable_days.clone();
+#[query(name = "getRegistry")]
+pub fn get_registry() -> (Option<Principal>, u64) {
+    STATE.with(|state| {
+        let state = state.borrow();
+        (state.registry, state.registry_version)
+    })
+}
+
+// Pays out the rewards a position has accrued in the pool, funded by the premiums the pool has received.
+#[update(name = "claimPayoutForLP")]
+pub async fn claim_payout_for_lp(pool_id: Nat, deposit_id: Nat) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let lp_contract = STATE.with(|state| {
+        let state = state.borrow();
+        ensure_configured(&state)?;
+        state.lp_contract.ok_or("LP contract address not set".to_string())
+    })?;
 
-    let mint_result: Result<(), _> = call(bqbtc_address, "mint", (caller, claimable_amount.clone())).await;
-    mint_result.map_err(|_| "Error minting BQ BTC".to_string())?;
+    let pay_result: Result<(Result<Nat, String>,), _> = call(lp_contract, "payRewards", (caller, pool_id.clone(), deposit_id.clone())).await;
+    let (paid,) = pay_result.map_err(|_| "Failed to pay rewards from the pool".to_string())?;
+    paid?;
 
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         state.lp_claims.entry(caller)
             .or_insert_with(HashMap::new)
-            .insert(pool_id, current_time);
+            .insert((pool_id, deposit_id), Nat::from(ic_cdk::api::time() / 1_000_000_000));
     });
 
     Ok(())
@@ -305,26 +775,28 @@ pub async fn get_user_participation(user: Principal) -> Result<Nat, String> {
 pub async fn delete_expired_user_covers(user: Principal) -> Result<(), String> {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let cover_ids = state.cover_ids.clone();
-        let user_covers = state.user_covers.get_mut(&user).ok_or("Error getting user covers")?;
-        
-        let current_time = Nat::from(ic_cdk::api::time() / 1_000_000_000);
+        let user_covers = user_covers_of(user);
+        if user_covers.is_empty() {
+            return Err("Error getting user covers".to_string());
+        }
 
-        let expired_ids: Vec<Nat> = cover_ids
-            .iter()
-            .filter_map(|id| {
-                if let Some(user_cover) = user_covers.get_mut(id) {
-                    if user_cover.is_active && current_time > user_cover.end_day {
-                        user_cover.is_active = false;
-                        return Some(id.clone());
-                    }
-                }
-                None
-            })
+        let current_time = Nat::from(ic_cdk::api::time() / 1_000_000_000);
+        let expired: Vec<GenericCoverInfo> = user_covers
+            .into_iter()
+            .filter(|user_cover| state.cover_ids.contains(&user_cover.cover_id))
+            .filter(|user_cover| user_cover.is_active && current_time > user_cover.end_day)
             .collect();
 
-        for id in expired_ids {
-            user_covers.remove(&id);
+        for user_cover in expired {
+            USER_COVERS.with(|covers| covers.borrow_mut().remove(&Candid((user, user_cover.cover_id.clone()))));
+            if let Some(cover) = state.covers.get_mut(&user_cover.cover_id) {
+                release_exposure(cover, &user_cover.cover_value);
+                cover.max_amount = if cover.capacity_amount > cover.cover_values {
+                    cover.capacity_amount.clone() - cover.cover_values.clone()
+                } else {
+                    Nat::from(0u64)
+                };
+            }
         }
 
         Ok(())
@@ -333,46 +805,43 @@ pub async fn delete_expired_user_covers(user: Principal) -> Result<(), String> {
 
 #[update(name = "updateMaxAmount")]
 pub async fn update_max_amount(cover_id: Nat) -> Result<(), String> {
-    let caller = ic_cdk::caller();
     let (cover, pool_contract) = STATE.with(| state | {
         let state = state.borrow();
-        let pool_contract = state.lp_contract.unwrap();
-        let cover = state.covers.get(&cover_id).ok_or("error getting cover").unwrap();
-        (cover.clone(), pool_contract)
-    });
+        let pool_contract = state.lp_contract.ok_or("LP contract address not set".to_string())?;
+        let cover = state.covers.get(&cover_id).ok_or("error getting cover")?;
+        Ok::<(Cover, Principal), String>((cover.clone(), pool_contract))
+    })?;
 
     if cover.capacity <= Nat::from(0u64) {
         return Err("Invalid cover capacity".to_string());
     }
 
-    let pool_details: Result<(Pool,), _> = call(pool_contract, "getPool", (caller, cover.pool_id.clone())).await;
+    let pool_details: Result<(Result<Pool, String>,), _> = call(pool_contract, "getPool", (cover.pool_id.clone(),)).await;
     let (pool, ) = pool_details.map_err(|_| "Failed to get pool")?;
-    let precision = Nat::from(1_000_000_000_000_000_000u128);
-    let amount = (pool.tvl * (cover.capacity * precision.clone() / Nat::from(100u64))) / precision;
+    let pool = pool?;
+    let amount = capacity_amount(&pool.tvl, &cover.capacity);
 
     STATE.with(| state | {
         let mut state = state.borrow_mut();
-        let cover = state.covers.get_mut(&cover_id).ok_or("error getting cover").unwrap();
+        let cover = state.covers.get_mut(&cover_id).ok_or("error getting cover")?;
         cover.capacity_amount = amount.clone();
-        cover.max_amount = amount - cover.cover_values.clone();
-    });
-
-    Ok(())
+        cover.max_amount = if amount > cover.cover_values {
+            amount - cover.cover_values.clone()
+        } else {
+            Nat::from(0u64)
+        };
+        Ok(())
+    })
 }
 
 #[query(name = "getAllUserCovers")]
 pub async fn get_all_user_covers(user: Principal) -> Result<Vec<GenericCoverInfo>, String> {
-    STATE.with(| state | {
-        let state = state.borrow();
-        let user_covers = state.user_covers.get(&user).ok_or_else(|| "User has no covers".to_string())?;
-        let covers: Vec<GenericCoverInfo> = user_covers
-            .values()
-            .filter(|user_cover| user_cover.cover_value > Nat::from(0u64))
-            .cloned()
-            .collect();
+    let user_covers = user_covers_of(user);
+    if user_covers.is_empty() {
+        return Err("User has no covers".to_string());
+    }
 
-        Ok(covers)
-    })
+    Ok(user_covers.into_iter().filter(|user_cover| user_cover.cover_value > Nat::from(0u64)).collect())
 }
 
 #[query(name = "getAllAvailableCovers")]
@@ -397,47 +866,14 @@ pub async fn get_cover_info(cover_id: Nat) -> Result<Cover, String> {
 
 #[query(name = "getUserCoverInfo")]
 pub async fn get_user_cover_info(user: Principal, cover_id: Nat) -> Result<GenericCoverInfo, String> {
-    STATE.with(| state | {
-        let state = state.borrow();
-        let cover = state.user_covers.get(&user).ok_or_else(|| "cover doesnt exist")?.get(&cover_id).ok_or_else(|| "user doesnt have this cover")?;
-
-        Ok(cover.clone())
-    })
-}
-
-#[query(name = "getDepositClaimableDays")]
-pub async fn get_deposit_claimable_days(user: Principal, pool_id: Nat) -> Result<Nat, String> {
-    let lp_contract = STATE.with(| state | {
-        let state = state.borrow();
-        let lp_contract = state.lp_contract.ok_or_else(|| "error getting pool canister id").unwrap();
-
-        lp_contract
-    });
-
-    let deposit_info_result: Result<(Deposit,), _> = call(lp_contract, "getUserDeposit", (pool_id.clone(), user)).await;
-    let (deposit_info,) = deposit_info_result.map_err(|_| "Failed to get user deposit information")?;
-    
-    let last_claim_time = STATE.with(|state| {
-        let state = state.borrow();
-        state.lp_claims.get(&user)
-            .and_then(|claims| claims.get(&pool_id).cloned())
-            .unwrap_or(deposit_info.start_date.clone())
-    });
-
-    let mut current_time = Nat::from(ic_cdk::api::time() / 1_000_000_000);
-    if current_time > deposit_info.expiry_date {
-        current_time = deposit_info.expiry_date;
-    }
-    let claimable_days = (current_time.clone() - last_claim_time.clone()) / Nat::from(86400u64); 
-
-    Ok(claimable_days)
+    find_user_cover(user, &cover_id).ok_or_else(|| "user doesnt have this cover".to_string())
 }
 
 #[query(name = "getLastClaimTime")]
-pub async fn get_last_claim_time(user: Principal, pool_id: Nat) -> Result<Nat, String> {
+pub async fn get_last_claim_time(user: Principal, pool_id: Nat, deposit_id: Nat) -> Result<Nat, String> {
     STATE.with(| state | {
         let state = state.borrow();
-        let last_claim = state.lp_claims.get(&user).ok_or_else(|| "user doesnt have claim yet")?.get(&pool_id).ok_or_else(|| "no claim made yet")?;
+        let last_claim = state.lp_claims.get(&user).ok_or_else(|| "user doesnt have claim yet")?.get(&(pool_id, deposit_id)).ok_or_else(|| "no claim made yet")?;
 
         Ok(last_claim.clone())
     })
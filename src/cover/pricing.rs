@@ -0,0 +1,114 @@
+use candid::Nat;
+
+use crate::types::{Cover, PricingCurve};
+use crate::BASIS_POINTS;
+
+const DAYS_PER_YEAR: u64 = 365;
+const DEFAULT_KINK_UTILIZATION_BPS: u64 = 8_000;
+
+// Curve used until one is configured: the cover's `cost` as the base annual rate, rising by half of it
+// up to 80% utilization and by a further three times it as the last of the capacity sells.
+pub fn default_curve(cover: &Cover) -> PricingCurve {
+    let base_rate_bps = cover.cost.clone() * Nat::from(100u64);
+    PricingCurve {
+        base_rate_bps: base_rate_bps.clone(),
+        kink_utilization_bps: Nat::from(DEFAULT_KINK_UTILIZATION_BPS),
+        slope_bps: base_rate_bps.clone() / Nat::from(2u64),
+        jump_slope_bps: base_rate_bps * Nat::from(3u64),
+    }
+}
+
+pub fn validate(curve: &PricingCurve) -> Result<(), String> {
+    let zero = Nat::from(0u64);
+    if curve.kink_utilization_bps == zero || curve.kink_utilization_bps > Nat::from(BASIS_POINTS) {
+        return Err("Kink utilization must be between 1 and 10000 basis points".to_string());
+    }
+    if curve.base_rate_bps == zero && curve.slope_bps == zero && curve.jump_slope_bps == zero {
+        return Err("Pricing curve must charge a non-zero rate".to_string());
+    }
+    Ok(())
+}
+
+pub fn utilization_bps(used: &Nat, capacity: &Nat) -> Nat {
+    let basis_points = Nat::from(BASIS_POINTS);
+    if *capacity == Nat::from(0u64) {
+        return basis_points;
+    }
+    (used.clone() * basis_points.clone() / capacity.clone()).min(basis_points)
+}
+
+// Annual rate at `utilization_bps`, following the kinked model used by lending markets:
+// the slope applies up to the kink and the steeper jump slope beyond it.
+pub fn rate_bps(curve: &PricingCurve, utilization_bps: &Nat) -> Nat {
+    let kink = curve.kink_utilization_bps.clone();
+    if *utilization_bps <= kink {
+        return curve.base_rate_bps.clone() + curve.slope_bps.clone() * utilization_bps.clone() / kink;
+    }
+
+    let excess = utilization_bps.clone() - kink.clone();
+    let headroom = Nat::from(BASIS_POINTS) - kink;
+    let jump = if headroom == Nat::from(0u64) {
+        curve.jump_slope_bps.clone()
+    } else {
+        curve.jump_slope_bps.clone() * excess / headroom
+    };
+    curve.base_rate_bps.clone() + curve.slope_bps.clone() + jump
+}
+
+// Premium for `cover_value` over `cover_period` days at an annual `rate_bps`. Rounded up so that no
+// non-zero cover is priced at nothing; a zero premium only comes out of a zero value or rate.
+pub fn premium(cover_value: &Nat, cover_period: &Nat, rate_bps: &Nat) -> Nat {
+    let denominator = Nat::from(DAYS_PER_YEAR * BASIS_POINTS);
+    let numerator = cover_value.clone() * rate_bps.clone() * cover_period.clone();
+    (numerator + denominator.clone() - Nat::from(1u64)) / denominator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{curve, nat};
+
+    #[test]
+    fn premium_is_exact_when_it_divides_evenly() {
+        // 5% a year on 1,000,000 for a full year.
+        assert_eq!(premium(&nat(1_000_000), &nat(365), &nat(500)), nat(50_000));
+    }
+
+    #[test]
+    fn premium_rounds_up_so_small_covers_are_never_free() {
+        assert_eq!(premium(&nat(1), &nat(1), &nat(1)), nat(1));
+        assert_eq!(premium(&nat(1_000_000), &nat(1), &nat(500)), nat(137));
+    }
+
+    #[test]
+    fn premium_is_zero_only_for_a_zero_value_period_or_rate() {
+        assert_eq!(premium(&nat(0), &nat(30), &nat(500)), nat(0));
+        assert_eq!(premium(&nat(1_000), &nat(0), &nat(500)), nat(0));
+        assert_eq!(premium(&nat(1_000), &nat(30), &nat(0)), nat(0));
+    }
+
+    #[test]
+    fn rate_follows_the_slope_up_to_the_kink_and_the_jump_beyond_it() {
+        assert_eq!(rate_bps(&curve(), &nat(0)), nat(500));
+        assert_eq!(rate_bps(&curve(), &nat(4_000)), nat(625));
+        assert_eq!(rate_bps(&curve(), &nat(8_000)), nat(750));
+        assert_eq!(rate_bps(&curve(), &nat(9_000)), nat(1_500));
+        assert_eq!(rate_bps(&curve(), &nat(BASIS_POINTS)), nat(2_250));
+    }
+
+    #[test]
+    fn utilization_is_capped_and_full_without_capacity() {
+        assert_eq!(utilization_bps(&nat(50), &nat(200)), nat(2_500));
+        assert_eq!(utilization_bps(&nat(300), &nat(200)), nat(BASIS_POINTS));
+        assert_eq!(utilization_bps(&nat(0), &nat(0)), nat(BASIS_POINTS));
+    }
+
+    #[test]
+    fn validate_rejects_curves_that_cannot_price() {
+        assert!(validate(&curve()).is_ok());
+        assert!(validate(&PricingCurve { kink_utilization_bps: nat(0), ..curve() }).is_err());
+        assert!(validate(&PricingCurve { kink_utilization_bps: nat(BASIS_POINTS + 1), ..curve() }).is_err());
+        let free = PricingCurve { base_rate_bps: nat(0), slope_bps: nat(0), jump_slope_bps: nat(0), ..curve() };
+        assert!(validate(&free).is_err());
+    }
+}
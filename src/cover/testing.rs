@@ -0,0 +1,18 @@
+// Fixtures shared by the cover canister's unit tests.
+use candid::Nat;
+
+use crate::types::PricingCurve;
+
+pub fn nat(value: u64) -> Nat {
+    Nat::from(value)
+}
+
+// 5% a year at no utilization, 7.5% at the 80% kink and 22.5% when fully sold.
+pub fn curve() -> PricingCurve {
+    PricingCurve {
+        base_rate_bps: nat(500),
+        kink_utilization_bps: nat(8_000),
+        slope_bps: nat(250),
+        jump_slope_bps: nat(1_500),
+    }
+}
@@ -0,0 +1,50 @@
+use candid::{Nat, Principal};
+use std::collections::HashMap;
+
+use crate::types::{Delegation, Proposal, ProposalKind, RiskType, Voter};
+
+// A delegation scoped to the proposal's risk type takes precedence over a delegation covering every risk type.
+// Parameter proposals carry no risk type and only follow unscoped delegations.
+pub fn resolve(delegations: &[Delegation], risk_type: Option<&RiskType>) -> Option<Principal> {
+    delegations
+        .iter()
+        .find(|delegation| risk_type.is_some() && delegation.risk_type.as_ref() == risk_type)
+        .or_else(|| delegations.iter().find(|delegation| delegation.risk_type.is_none()))
+        .map(|delegation| delegation.delegate)
+}
+
+pub fn scope(proposal: &Proposal) -> Option<&RiskType> {
+    match proposal.kind {
+        ProposalKind::Claim => Some(&proposal.proposal_param.risk_type),
+        ProposalKind::Action(_) => None,
+    }
+}
+
+pub fn set(delegations: &mut Vec<Delegation>, delegate: Principal, risk_type: Option<RiskType>) {
+    delegations.retain(|delegation| delegation.risk_type != risk_type);
+    delegations.push(Delegation { delegate, risk_type });
+}
+
+// Splits a winning voter's reward between the delegate and its delegators in proportion to the weight each contributed.
+pub fn split_reward(reward: &Nat, voter: Principal, voters: &HashMap<Principal, Voter>) -> Vec<(Principal, Nat)> {
+    let zero = Nat::from(0u64);
+    let Some(record) = voters.get(&voter) else {
+        return vec![(voter, reward.clone())];
+    };
+    if record.delegators.is_empty() || record.weight == zero {
+        return vec![(voter, reward.clone())];
+    }
+
+    let mut shares = vec![];
+    let mut remaining = reward.clone();
+    for delegator in record.delegators.iter() {
+        let weight = voters.get(delegator).map(|v| v.weight.clone()).unwrap_or_else(|| zero.clone());
+        let share = reward.clone() * weight / record.weight.clone();
+        if share > zero {
+            remaining -= share.clone();
+            shares.push((*delegator, share));
+        }
+    }
+    shares.push((voter, remaining));
+    shares
+}
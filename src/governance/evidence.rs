@@ -0,0 +1,200 @@
+use candid::{Nat, Principal};
+use ic_cdk::api::call::call_with_payment128;
+use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, GetUtxosRequest, GetUtxosResponse, UtxoFilter};
+
+use crate::types::ClaimEvidence;
+
+// Matches the fee the management canister charges for bitcoin_get_utxos on mainnet; any excess is refunded.
+const GET_UTXOS_CYCLES: u128 = 10_000_000_000;
+const MAX_UTXO_PAGES: usize = 10;
+// A payment shallower than this could still be reorganised away, so it is not accepted as evidence.
+const MIN_CONFIRMATIONS: u32 = 6;
+
+// Parses a transaction id as displayed by block explorers into the byte order used by the Bitcoin API.
+pub fn parse_txid(tx_hash: &str) -> Result<Vec<u8>, String> {
+    let tx_hash = tx_hash.trim().trim_start_matches("0x");
+    // Checked up front because from_str_radix would also accept a sign such as "+f".
+    if tx_hash.len() != 64 || !tx_hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err("Transaction hash must be 32 bytes of hex".to_string());
+    }
+
+    let mut txid = (0..tx_hash.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&tx_hash[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| "Transaction hash must be 32 bytes of hex".to_string())?;
+    txid.reverse();
+    Ok(txid)
+}
+
+// Running total of the outputs a transaction paid to the address, accumulated page by page.
+#[derive(Default)]
+struct Tally {
+    amount: u64,
+    block_height: Option<u32>,
+    tip_height: u32,
+}
+
+impl Tally {
+    fn add_page(&mut self, response: &GetUtxosResponse, txid: &[u8]) {
+        self.tip_height = response.tip_height;
+        for utxo in response.utxos.iter().filter(|utxo| utxo.outpoint.txid == txid) {
+            self.amount = self.amount.saturating_add(utxo.value);
+            self.block_height = Some(utxo.height);
+        }
+    }
+
+    fn into_evidence(self, tx_hash: String, address: String, now: u64) -> Result<ClaimEvidence, String> {
+        let block_height = self.block_height
+            .ok_or("Transaction not found among unspent outputs of the address; spent outputs cannot be verified".to_string())?;
+        let confirmations = self.tip_height.saturating_sub(block_height).saturating_add(1);
+        if confirmations < MIN_CONFIRMATIONS {
+            return Err(format!("Transaction has {} confirmations, at least {} are required", confirmations, MIN_CONFIRMATIONS));
+        }
+        Ok(ClaimEvidence {
+            tx_hash,
+            btc_address: address,
+            amount: self.amount,
+            block_height,
+            confirmations,
+            verified_at: Nat::from(now),
+        })
+    }
+}
+
+// Looks up the outputs `txid` paid to `address` and summarises them as evidence. bitcoin_get_utxos only
+// lists unspent outputs and the canister tracks the chain itself, so block headers are not re-checked here;
+// a payment the address has since spent cannot be found this way, and voters decide such claims without it.
+pub async fn fetch(
+    bitcoin_canister: Principal,
+    network: BitcoinNetwork,
+    address: String,
+    txid: &[u8],
+    tx_hash: String,
+    now: u64,
+) -> Result<ClaimEvidence, String> {
+    let mut filter = None;
+    let mut tally = Tally::default();
+
+    for _ in 0..MAX_UTXO_PAGES {
+        let request = GetUtxosRequest { address: address.clone(), network, filter };
+        let result: Result<(GetUtxosResponse,), _> = call_with_payment128(bitcoin_canister, "bitcoin_get_utxos", (request,), GET_UTXOS_CYCLES).await;
+        let (response,) = result.map_err(|(_, message)| format!("Failed to query Bitcoin UTXOs: {}", message))?;
+
+        tally.add_page(&response, txid);
+        match response.next_page {
+            Some(page) => filter = Some(UtxoFilter::Page(page)),
+            None => break,
+        }
+    }
+
+    tally.into_evidence(tx_hash, address, now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::api::management_canister::bitcoin::{Outpoint, Utxo};
+    use shared::utxos;
+
+    const TX_HASH: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    // Stands in for a page served by the Bitcoin canister.
+    fn page(tip_height: u32, utxos: Vec<(Vec<u8>, u64, u32)>) -> GetUtxosResponse {
+        GetUtxosResponse {
+            utxos: utxos
+                .into_iter()
+                .enumerate()
+                .map(|(vout, (txid, value, height))| Utxo { outpoint: Outpoint { txid, vout: vout as u32 }, value, height })
+                .collect(),
+            tip_block_hash: vec![0; 32],
+            tip_height,
+            next_page: None,
+        }
+    }
+
+    #[test]
+    fn parse_txid_reverses_display_order() {
+        let txid = parse_txid(&format!("0x{}", TX_HASH)).unwrap();
+        assert_eq!(txid.len(), 32);
+        assert_eq!(txid[0], 0xef);
+        assert_eq!(txid[31], 0x01);
+    }
+
+    // Reads every page the local Bitcoin mock serves for the address, as fetch does against the Bitcoin canister.
+    fn tally_from_mock(utxos: &[Utxo], tip_height: u32, txid: &[u8]) -> Tally {
+        let mut tally = Tally::default();
+        let mut filter = None;
+        loop {
+            let response = utxos::page(utxos, tip_height, filter);
+            tally.add_page(&response, txid);
+            match response.next_page {
+                Some(page) => filter = Some(UtxoFilter::Page(page)),
+                None => return tally,
+            }
+        }
+    }
+
+    fn utxo(txid: &[u8], vout: u32, value: u64, height: u32) -> Utxo {
+        Utxo { outpoint: Outpoint { txid: txid.to_vec(), vout }, value, height }
+    }
+
+    #[test]
+    fn parse_txid_rejects_malformed_hashes() {
+        assert!(parse_txid("abcd").is_err());
+        assert!(parse_txid(&"zz".repeat(32)).is_err());
+        assert!(parse_txid(&"+f".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn tally_sums_matching_outputs_across_pages() {
+        let txid = parse_txid(TX_HASH).unwrap();
+        let other = vec![7u8; 32];
+        let mut tally = Tally::default();
+        tally.add_page(&page(100, vec![(txid.clone(), 5_000, 95), (other, 9_999, 90)]), &txid);
+        tally.add_page(&page(101, vec![(txid.clone(), 2_500, 95)]), &txid);
+
+        let evidence = tally.into_evidence(TX_HASH.to_string(), "bc1qclaimant".to_string(), 42).unwrap();
+        assert_eq!(evidence.amount, 7_500);
+        assert_eq!(evidence.block_height, 95);
+        assert_eq!(evidence.confirmations, 7);
+        assert_eq!(evidence.btc_address, "bc1qclaimant");
+    }
+
+    #[test]
+    fn tally_without_matching_outputs_is_not_evidence() {
+        let txid = parse_txid(TX_HASH).unwrap();
+        let mut tally = Tally::default();
+        tally.add_page(&page(100, vec![(vec![1u8; 32], 5_000, 95)]), &txid);
+        assert!(tally.into_evidence(TX_HASH.to_string(), "bc1qclaimant".to_string(), 42).is_err());
+    }
+
+    #[test]
+    fn payment_spread_over_several_mock_pages_is_summed() {
+        let txid = parse_txid(TX_HASH).unwrap();
+        let mut seeded: Vec<Utxo> = (0..utxos::PAGE_SIZE as u32).map(|vout| utxo(&[9u8; 32], vout, 1, 90)).collect();
+        seeded.push(utxo(&txid, 0, 4_000, 95));
+        seeded.push(utxo(&txid, 1, 1_000, 95));
+
+        let evidence = tally_from_mock(&seeded, 100, &txid).into_evidence(TX_HASH.to_string(), "bc1qclaimant".to_string(), 42).unwrap();
+        assert_eq!(evidence.amount, 5_000);
+        assert_eq!(evidence.confirmations, 6);
+    }
+
+    #[test]
+    fn spent_payment_is_not_found_in_the_mock() {
+        let txid = parse_txid(TX_HASH).unwrap();
+        // The address has since spent the claimed output and only holds its change.
+        let seeded = vec![utxo(&[3u8; 32], 0, 2_000, 99)];
+        let tally = tally_from_mock(&seeded, 100, &txid);
+        assert!(tally.into_evidence(TX_HASH.to_string(), "bc1qclaimant".to_string(), 42).is_err());
+    }
+
+    #[test]
+    fn shallow_payment_in_the_mock_is_not_evidence() {
+        let txid = parse_txid(TX_HASH).unwrap();
+        let seeded = vec![utxo(&txid, 0, 5_000, 98)];
+        let tally = tally_from_mock(&seeded, 100, &txid);
+        assert!(tally.into_evidence(TX_HASH.to_string(), "bc1qclaimant".to_string(), 42).is_err());
+    }
+}
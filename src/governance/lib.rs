@@ -1,117 +1,697 @@
 use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_cdk::api::call::call;
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
 use ic_cdk_macros::*;
+use ic_stable_structures::StableBTreeMap;
+use shared::stable::{self, Candid, Memory};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
+mod delegation;
+mod evidence;
+mod lifecycle;
+mod roles;
+mod staking;
+#[cfg(test)]
+mod testing;
 mod types;
-use types::{GenericCoverInfo, Proposal, ProposalParam, ProposalStatus, Voter};
+use types::{
+    ClaimEvidence, Delegation, GenericCoverInfo, GovernanceAction, Proposal, ProposalKind, ProposalParam, ProposalStatus,
+    ProtocolCanisters, QueueStatus, QueuedAction, RiskType, Role, RoleChange, StakeCheckpoint, StakeInfo, StatusChange,
+    TxReceipt, Voter, VotingRules,
+};
+
+const DEFAULT_TIMELOCK_DELAY_SECS: u64 = 2 * 24 * 60 * 60;
+const MAX_EVIDENCE_ATTEMPTS: u32 = 3;
+const FINALIZE_RETRY_SECS: u64 = 60;
+const MAX_FINALIZE_RETRY_SECS: u64 = 24 * 60 * 60;
+const PROPOSALS_MEMORY: u8 = 1;
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::default());
+
+    // Proposals carry their voter lists and status history and are never pruned, so they live in stable
+    // memory instead of being copied in and out of the heap on each upgrade.
+    static PROPOSALS: RefCell<StableBTreeMap<Candid<Nat>, Candid<Proposal>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(PROPOSALS_MEMORY)));
+
+    static ACTIONS_IN_FLIGHT: RefCell<HashSet<Nat>> = RefCell::default();
 }
 
 #[derive(CandidType, Deserialize, Default)]
 struct State {
-    proposals: HashMap<Nat, Proposal>,
     proposal_counter: Nat,
     voting_duration: u64,
     reward_amount: Nat,
     voters: HashMap<Nat, HashMap<Principal, Voter>>,
     participants: Vec<Principal>,
     participation: HashMap<Principal, Nat>,
-    is_admin: HashMap<Principal, bool>,
+    roles: HashMap<Role, Vec<Principal>>,
+    role_history: Vec<RoleChange>,
     governance_token: Option<Principal>,
-    lp_contract: Option<Principal>,
     bqbtc_contract: Option<Principal>,
     cover_contract: Option<Principal>,
     pool_contract: Option<Principal>,
+    stakes: HashMap<Principal, Vec<StakeCheckpoint>>,
+    stake_locks: HashMap<Principal, Nat>,
+    delegations: HashMap<Principal, Vec<Delegation>>,
+    total_staked: Vec<StakeCheckpoint>,
+    voting_rules: VotingRules,
+    bitcoin_canister: Option<Principal>,
+    bitcoin_network: Option<BitcoinNetwork>,
+    evidence_attempts: HashMap<Nat, u32>,
+    queue: HashMap<Nat, QueuedAction>,
+    queue_counter: Nat,
+    timelock_delay: u64,
+    registry: Option<Principal>,
+    registry_version: u64,
+}
+
+// Layouts the state has been saved in across upgrades; post_upgrade migrates older ones forward.
+#[derive(CandidType, Deserialize)]
+enum StableState {
+    V1(State),
+}
+
+fn find_proposal(proposal_id: &Nat) -> Result<Proposal, String> {
+    PROPOSALS.with(|proposals| proposals.borrow().get(&Candid(proposal_id.clone())))
+        .map(|Candid(proposal)| proposal)
+        .ok_or("Proposal not found".to_string())
+}
+
+fn save_proposal(proposal: Proposal) {
+    PROPOSALS.with(|proposals| proposals.borrow_mut().insert(Candid(proposal.id.clone()), Candid(proposal)));
+}
+
+// Applies `change` to a stored proposal, writing it back only when the change succeeds.
+fn update_proposal<T>(proposal_id: &Nat, change: impl FnOnce(&mut Proposal) -> Result<T, String>) -> Result<T, String> {
+    let mut proposal = find_proposal(proposal_id)?;
+    let result = change(&mut proposal)?;
+    save_proposal(proposal);
+    Ok(result)
+}
+
+fn all_proposals() -> Vec<Proposal> {
+    PROPOSALS.with(|proposals| proposals.borrow().iter().map(|(_, Candid(proposal))| proposal).collect())
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CanisterIds {
+    governance_token: Option<Principal>,
+    bqbtc: Option<Principal>,
+    cover: Option<Principal>,
+    pool: Option<Principal>,
+}
+
+// User-facing endpoints refuse to run until every peer canister has been wired up.
+fn ensure_configured(state: &State) -> Result<(), String> {
+    let missing: Vec<&str> = [
+        ("governance token", state.governance_token),
+        ("bqBTC", state.bqbtc_contract),
+        ("cover", state.cover_contract),
+        ("pool", state.pool_contract),
+    ]
+    .iter()
+    .filter(|(_, id)| id.is_none())
+    .map(|(name, _)| *name)
+    .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Governance canister is not configured: missing {}", missing.join(", ")))
+    }
 }
 
 #[init]
 fn init(owner: Principal, governance_token: Principal, lp_contract: Principal, voting_duration_minutes: u64) {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        state.is_admin.insert(owner, true);
+        let now = ic_cdk::api::time() / 1_000_000_000;
+        let State { roles: members, role_history, .. } = &mut *state;
+        for role in [Role::Admin, Role::Guardian] {
+            roles::grant(members, role_history, role, owner, owner, now).expect("Failed to grant initial role");
+        }
+        state.timelock_delay = DEFAULT_TIMELOCK_DELAY_SECS;
         state.voting_duration = voting_duration_minutes * 60;
         state.governance_token = Some(governance_token);
-        state.lp_contract = Some(lp_contract);
+        state.pool_contract = Some(lp_contract);
         state.reward_amount = Nat::from(100u64 * 10u64.pow(18));
+        state.voting_rules = VotingRules {
+            quorum_bps: 2_000,
+            approval_threshold_bps: 5_000,
+            large_claim_threshold_bps: 6_667,
+            large_claim_tvl_bps: 1_000,
+        };
     });
 }
 
-#[update(name = "createProposal")]
-pub async fn create_proposal(params: ProposalParam) -> Result<(), String> {
-    let caller = ic_cdk::caller();
+#[pre_upgrade]
+fn pre_upgrade() {
+    STATE.with(|state| stable::save_state(&StableState::V1(std::mem::take(&mut *state.borrow_mut()))));
+}
 
-    let cover_contract = STATE.with(|state| state.borrow().cover_contract.unwrap());
-    let cover_info: Result<(GenericCoverInfo,), _> = call(cover_contract, "getUserCoverInfo", (params.user, params.cover_id.clone())).await;
-    let (cover,) = cover_info.map_err(|_| "Failed to retrieve cover info".to_string())?;
-    if params.claim_amount > cover.cover_value {
-        return Err("Claim amount exceeds cover value".to_string());
+#[post_upgrade]
+fn post_upgrade() {
+    let saved = match stable::restore_state() {
+        Some(StableState::V1(saved)) => saved,
+        None => {
+            ic_cdk::println!("No saved state found, starting from an empty state");
+            State::default()
+        },
+    };
+    STATE.with(|state| *state.borrow_mut() = saved);
+    if STATE.with(|state| state.borrow().registry.is_some()) {
+        schedule_registry_sync();
     }
 
-    STATE.with(|state| {
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    let pending: Vec<(Nat, Nat)> = all_proposals()
+        .iter()
+        .filter(|proposal| proposal.status == ProposalStatus::Pending)
+        .map(|proposal| (proposal.id.clone(), lifecycle::time_left(proposal, now)))
+        .collect();
+    for (proposal_id, time_left) in pending {
+        schedule_execution(proposal_id, u64::try_from(&time_left.0).unwrap_or(u64::MAX));
+    }
+
+    let queued: Vec<(Nat, Nat)> = STATE.with(|state| {
+        state.borrow().queue
+            .values()
+            .filter(|queued| queued.status == QueueStatus::Queued)
+            .map(|queued| (queued.id.clone(), queued.eta.clone()))
+            .collect()
+    });
+    for (queue_id, eta) in queued {
+        let eta = u64::try_from(&eta.0).unwrap_or(u64::MAX);
+        schedule_queued_action(queue_id, eta.saturating_sub(now));
+    }
+}
+
+// Timers do not survive upgrades, so every pending proposal is re-armed in post_upgrade.
+fn schedule_execution(proposal_id: Nat, delay_secs: u64) {
+    schedule_finalization(proposal_id, delay_secs.saturating_add(1), FINALIZE_RETRY_SECS);
+}
+
+// A proposal that fails to finalize is tried again after `retry_secs`, doubling up to a day, for as long
+// as it is still pending.
+fn schedule_finalization(proposal_id: Nat, delay_secs: u64, retry_secs: u64) {
+    ic_cdk_timers::set_timer(Duration::from_secs(delay_secs), move || {
+        ic_cdk::spawn(async move {
+            if let Err(err) = finalize_proposal(proposal_id.clone()).await {
+                ic_cdk::println!("Failed to execute proposal {}: {}", proposal_id, err);
+                if find_proposal(&proposal_id).map_or(false, |proposal| proposal.status == ProposalStatus::Pending) {
+                    let next_retry_secs = retry_secs.saturating_mul(2).min(MAX_FINALIZE_RETRY_SECS);
+                    schedule_finalization(proposal_id, retry_secs, next_retry_secs);
+                }
+            }
+        });
+    });
+}
+
+fn has_role(state: &State, role: Role, principal: &Principal) -> bool {
+    roles::has_role(&state.roles, role, principal)
+}
+
+fn schedule_queued_action(queue_id: Nat, delay_secs: u64) {
+    ic_cdk_timers::set_timer(Duration::from_secs(delay_secs), move || {
+        ic_cdk::spawn(async move {
+            if let Err(err) = run_queued_action(queue_id.clone()).await {
+                ic_cdk::println!("Failed to execute queued action {}: {}", queue_id, err);
+            }
+        });
+    });
+}
+
+// Every privileged change waits out the timelock so users have time to react before it takes effect.
+fn enqueue_action(action: GovernanceAction, proposal_id: Option<Nat>, queued_by: Principal) -> Nat {
+    let (queue_id, delay) = STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let proposal_id = state.proposal_counter.clone() + Nat::from(1u64);
-        state.proposal_counter = proposal_id.clone();
-        state.proposals.insert(proposal_id.clone(), Proposal {
-            id: proposal_id.clone(),
-            votes_for: Nat::from(0u64),
-            votes_against: Nat::from(0u64),
-            created_at: Nat::from(ic_cdk::api::time() / 1_000_000_000),
-            deadline: Nat::from(0u64),
-            timeleft: Nat::from(0u64),
-            executed: false,
-            status: ProposalStatus::Submitted,
-            proposal_param: params,
-            voters_for: vec![],
-            voters_against: vec![]
+        let now = ic_cdk::api::time() / 1_000_000_000;
+        let queue_id = state.queue_counter.clone() + Nat::from(1u64);
+        let delay = state.timelock_delay;
+        state.queue_counter = queue_id.clone();
+        state.queue.insert(queue_id.clone(), QueuedAction {
+            id: queue_id.clone(),
+            action,
+            proposal_id,
+            queued_by,
+            queued_at: Nat::from(now),
+            eta: Nat::from(now + delay),
+            status: QueueStatus::Queued,
+            resolved_at: None,
+            error: None,
         });
+        (queue_id, delay)
+    });
+
+    schedule_queued_action(queue_id.clone(), delay);
+    queue_id
+}
+
+// Held while a queued action runs so a concurrent trigger cannot run it twice. Dropping it, including
+// during the cleanup of a trapped callback, leaves the action queued for another attempt.
+struct ActionGuard {
+    queue_id: Nat,
+}
+
+impl ActionGuard {
+    fn acquire(queue_id: &Nat) -> Result<Self, String> {
+        ACTIONS_IN_FLIGHT.with(|in_flight| {
+            if !in_flight.borrow_mut().insert(queue_id.clone()) {
+                return Err("Queued action is already running".to_string());
+            }
+            Ok(ActionGuard { queue_id: queue_id.clone() })
+        })
+    }
+}
 
-        if !state.participants.contains(&caller) {
-            state.participants.push(caller);
+impl Drop for ActionGuard {
+    fn drop(&mut self) {
+        ACTIONS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.queue_id));
+    }
+}
+
+// The entry stays Queued until the action has run, and only then moves to Executed or Failed.
+async fn run_queued_action(queue_id: Nat) -> Result<(), String> {
+    let _guard = ActionGuard::acquire(&queue_id)?;
+    let (action, proposal_id) = STATE.with(|state| {
+        let state = state.borrow();
+        let queued = state.queue.get(&queue_id).ok_or("Queued action not found".to_string())?;
+        if queued.status != QueueStatus::Queued {
+            return Err("Action is not queued".to_string());
         }
-        *state.participation.entry(caller).or_insert(Nat::from(0u64)) += Nat::from(1u64);
+        if Nat::from(ic_cdk::api::time() / 1_000_000_000) < queued.eta {
+            return Err("Timelock has not elapsed".to_string());
+        }
+        Ok::<(GovernanceAction, Option<Nat>), String>((queued.action.clone(), queued.proposal_id.clone()))
+    })?;
 
+    let result = execute_action(action).await;
+
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    STATE.with(|state| {
+        if let Some(queued) = state.borrow_mut().queue.get_mut(&queue_id) {
+            queued.resolved_at = Some(Nat::from(now));
+            match &result {
+                Ok(()) => queued.status = QueueStatus::Executed,
+                Err(err) => {
+                    queued.status = QueueStatus::Failed;
+                    queued.error = Some(err.clone());
+                },
+            }
+        }
+    });
+
+    if let Some(proposal_id) = proposal_id {
+        update_proposal(&proposal_id, |proposal| match &result {
+            Ok(()) => lifecycle::transition(proposal, ProposalStatus::Executed, now),
+            Err(err) => {
+                proposal.execution_error = Some(err.clone());
+                Ok(())
+            },
+        })?;
+    }
+
+    result
+}
+
+#[update(name = "executeQueuedAction")]
+pub async fn execute_queued_action(queue_id: Nat) -> Result<(), String> {
+    run_queued_action(queue_id).await
+}
+
+#[update(name = "cancelQueuedAction")]
+pub fn cancel_queued_action(queue_id: Nat) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !has_role(&state, Role::Guardian, &caller) {
+            return Err("Only a guardian can cancel queued actions".to_string());
+        }
+        if ACTIONS_IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&queue_id)) {
+            return Err("Queued action is already running".to_string());
+        }
+        let now = ic_cdk::api::time() / 1_000_000_000;
+        let queued = state.queue.get(&queue_id).ok_or("Queued action not found".to_string())?;
+        if queued.status != QueueStatus::Queued {
+            return Err("Action is not queued".to_string());
+        }
+
+        // Every check runs before anything is written, so a refused cancellation leaves both records as they were.
+        let cancelled_proposal = match &queued.proposal_id {
+            Some(proposal_id) => {
+                let mut proposal = find_proposal(proposal_id)?;
+                lifecycle::transition(&mut proposal, ProposalStatus::Cancelled, now)?;
+                Some(proposal)
+            },
+            None => None,
+        };
+
+        let queued = state.queue.get_mut(&queue_id).ok_or("Queued action not found".to_string())?;
+        queued.status = QueueStatus::Cancelled;
+        queued.resolved_at = Some(Nat::from(now));
+        if let Some(proposal) = cancelled_proposal {
+            save_proposal(proposal);
+        }
         Ok(())
     })
 }
 
-#[update(name = "vote")]
-pub async fn vote(proposal_id: Nat, in_favor: bool) -> Result<(), String> {
+#[query(name = "getQueuedActions")]
+pub fn get_queued_actions() -> Vec<QueuedAction> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let mut queued: Vec<QueuedAction> = state.queue
+            .values()
+            .filter(|queued| queued.status == QueueStatus::Queued)
+            .cloned()
+            .collect();
+        queued.sort_by(|a, b| a.eta.cmp(&b.eta));
+        queued
+    })
+}
+
+#[query(name = "getActionHistory")]
+pub fn get_action_history() -> Vec<QueuedAction> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let mut history: Vec<QueuedAction> = state.queue.values().cloned().collect();
+        history.sort_by(|a, b| a.id.cmp(&b.id));
+        history
+    })
+}
+
+#[query(name = "getTimelockDelay")]
+pub fn get_timelock_delay() -> u64 {
+    STATE.with(|state| state.borrow().timelock_delay)
+}
+
+#[update(name = "createProposal")]
+pub async fn create_proposal(params: ProposalParam) -> Result<Nat, String> {
     let caller = ic_cdk::caller();
-    let bqbtc_canister = STATE.with(|state| {
+
+    let (cover_contract, pool_contract) = STATE.with(|state| {
         let state = state.borrow();
-        state.bqbtc_contract.ok_or("bqBTC canister address not set")
+        if !has_role(&state, Role::ClaimsExecutor, &caller) {
+            return Err("Claims must be filed through the claims canister".to_string());
+        }
+        ensure_configured(&state)?;
+        let cover_contract = state.cover_contract.ok_or("Cover canister address not set".to_string())?;
+        let pool_contract = state.pool_contract.ok_or("Pool canister address not set".to_string())?;
+        Ok::<(Principal, Principal), String>((cover_contract, pool_contract))
     })?;
+    evidence::parse_txid(&params.tx_hash)?;
+    if params.btc_address.trim().is_empty() {
+        return Err("Claims must name the Bitcoin address the loss was paid to".to_string());
+    }
 
-    let weight: Result<(Nat,), _> = call(bqbtc_canister, "balanceOf", (caller, caller)).await;
-    let voter_weight = weight.map_err(|_| "Failed to retrieve voting weight")?.0;
+    let cover_info: Result<(Result<GenericCoverInfo, String>,), _> = call(cover_contract, "getUserCoverInfo", (params.user, params.cover_id.clone())).await;
+    let (cover,) = cover_info.map_err(|_| "Failed to retrieve cover info".to_string())?;
+    let cover = cover?;
+    if params.claim_amount > cover.cover_value {
+        return Err("Claim amount exceeds cover value".to_string());
+    }
+
+    let tvl_result: Result<(Result<Nat, String>,), _> = call(pool_contract, "getPoolTVL", (params.pool_id.clone(),)).await;
+    let (pool_tvl,) = tvl_result.map_err(|_| "Failed to retrieve pool TVL".to_string())?;
+    let pool_tvl = pool_tvl?;
+
+    let proposal_id = STATE.with(|state| open_proposal(&mut state.borrow_mut(), params, ProposalKind::Claim, Some(pool_tvl)))?;
+    let voting_duration = STATE.with(|state| state.borrow().voting_duration);
+    schedule_execution(proposal_id.clone(), voting_duration);
+    Ok(proposal_id)
+}
+
+#[update(name = "createGovernanceProposal")]
+pub async fn create_governance_proposal(action: GovernanceAction, description: String) -> Result<Nat, String> {
+    let caller = ic_cdk::caller();
+    let (proposal_id, voting_duration) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        ensure_configured(&state)?;
+        let staked = state.stakes.get(&caller).map(|history| staking::current_stake(history)).unwrap_or_else(|| Nat::from(0u64));
+        if staked == Nat::from(0u64) {
+            return Err("Only BQ stakers can propose parameter changes".to_string());
+        }
+        if matches!(action, GovernanceAction::ReleaseClaimPayout { .. }) {
+            return Err("Claim payouts are released by their own claim proposal".to_string());
+        }
+        if let GovernanceAction::SetVotingRules { rules } = &action {
+            validate_voting_rules(rules)?;
+        }
+
+        let pool_id = match &action {
+            GovernanceAction::UpdatePool { pool_id, .. } | GovernanceAction::DeactivatePool { pool_id } => pool_id.clone(),
+            _ => Nat::from(0u64),
+        };
+        // Parameter proposals reuse the claim parameters only for the proposer, description and affected pool.
+        let params = ProposalParam {
+            user: caller,
+            risk_type: RiskType::Protocol,
+            cover_id: Nat::from(0u64),
+            tx_hash: String::new(),
+            btc_address: String::new(),
+            description,
+            pool_id,
+            claim_amount: Nat::from(0u64),
+        };
+        let proposal_id = open_proposal(&mut state, params, ProposalKind::Action(action), None)?;
+        Ok::<(Nat, u64), String>((proposal_id, state.voting_duration))
+    })?;
+
+    schedule_execution(proposal_id.clone(), voting_duration);
+    Ok(proposal_id)
+}
+
+fn open_proposal(state: &mut State, params: ProposalParam, kind: ProposalKind, pool_tvl: Option<Nat>) -> Result<Nat, String> {
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    let proposer = params.user;
+    let proposal_id = state.proposal_counter.clone() + Nat::from(1u64);
+    let mut proposal = Proposal {
+        id: proposal_id.clone(),
+        votes_for: Nat::from(0u64),
+        votes_against: Nat::from(0u64),
+        created_at: Nat::from(now),
+        deadline: Nat::from(0u64),
+        timeleft: Nat::from(0u64),
+        executed: false,
+        status: ProposalStatus::Submitted,
+        proposal_param: params,
+        voters_for: vec![],
+        voters_against: vec![],
+        status_history: vec![StatusChange { status: ProposalStatus::Submitted, timestamp: Nat::from(now) }],
+        snapshot_at: Nat::from(0u64),
+        quorum: Nat::from(0u64),
+        approval_threshold_bps: Nat::from(0u64),
+        evidence: None,
+        kind,
+        execution_error: None,
+    };
+    lifecycle::open_voting(&mut proposal, state.voting_duration, now)?;
+    let staked_supply = staking::stake_at(&state.total_staked, &proposal.snapshot_at);
+    lifecycle::apply_rules(&mut proposal, &state.voting_rules, staked_supply, pool_tvl);
+
+    state.proposal_counter = proposal_id.clone();
+    save_proposal(proposal);
+
+    if !state.participants.contains(&proposer) {
+        state.participants.push(proposer);
+    }
+    *state.participation.entry(proposer).or_insert(Nat::from(0u64)) += Nat::from(1u64);
+
+    Ok(proposal_id)
+}
 
-    let (mut proposal, has_voted) = STATE.with(|state| {
+#[update(name = "stake")]
+pub async fn stake(amount: Nat) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if amount == Nat::from(0u64) {
+        return Err("Stake amount must be greater than zero".to_string());
+    }
+    let governance_token = STATE.with(|state| {
         let state = state.borrow();
-        let proposal = state.proposals.get(&proposal_id).cloned().ok_or("Proposal not found")?;
-        let has_voted = state
-            .voters
-            .get(&proposal_id)
-            .and_then(|v| v.get(&caller))
-            .map(|v| v.voted)
-            .unwrap_or(false);
-        Ok::<(Proposal, bool), String>((proposal, has_voted))
+        ensure_configured(&state)?;
+        state.governance_token.ok_or("Governance token address not set".to_string())
     })?;
 
-    if has_voted {
-        return Err("Already voted".to_string());
+    let transfer_result: Result<(TxReceipt,), _> = call(governance_token, "transferFrom", (caller, ic_cdk::id(), amount.clone())).await;
+    match transfer_result {
+        Ok((Ok(_),)) => {},
+        Ok((Err(err),)) => return Err(format!("Failed to transfer governance tokens: {:?}", err)),
+        Err(_) => return Err("Failed to call governance token".to_string()),
     }
 
-    if ic_cdk::api::time() / 1_000_000_000 >= proposal.deadline && proposal.status != ProposalStatus::Submitted {
-        return Err("Voting period elapsed".to_string());
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let now = ic_cdk::api::time() / 1_000_000_000;
+        let history = state.stakes.entry(caller).or_default();
+        let staked = staking::current_stake(history) + amount.clone();
+        staking::checkpoint(history, staked, now);
+        let total = staking::current_stake(&state.total_staked) + amount;
+        staking::checkpoint(&mut state.total_staked, total, now);
+    });
+
+    Ok(())
+}
+
+#[update(name = "unstake")]
+pub async fn unstake(amount: Nat) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let governance_token = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let governance_token = state.governance_token.ok_or("Governance token address not set".to_string())?;
+        let now = ic_cdk::api::time() / 1_000_000_000;
+
+        let locked_until = state.stake_locks.get(&caller).cloned().unwrap_or_else(|| Nat::from(0u64));
+        if Nat::from(now) <= locked_until {
+            return Err("Stake is locked until active votes close".to_string());
+        }
+
+        let history = state.stakes.entry(caller).or_default();
+        let staked = staking::current_stake(history);
+        if staked < amount {
+            return Err("Insufficient staked balance".to_string());
+        }
+        staking::checkpoint(history, staked - amount.clone(), now);
+        let total = staking::current_stake(&state.total_staked) - amount.clone();
+        staking::checkpoint(&mut state.total_staked, total, now);
+        Ok(governance_token)
+    })?;
+
+    let transfer_result: Result<(TxReceipt,), _> = call(governance_token, "transfer", (caller, amount.clone())).await;
+    if !matches!(transfer_result, Ok((Ok(_),))) {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            let now = ic_cdk::api::time() / 1_000_000_000;
+            let history = state.stakes.entry(caller).or_default();
+            let staked = staking::current_stake(history) + amount.clone();
+            staking::checkpoint(history, staked, now);
+            let total = staking::current_stake(&state.total_staked) + amount;
+            staking::checkpoint(&mut state.total_staked, total, now);
+        });
+        return Err("Failed to return governance tokens".to_string());
     }
 
+    Ok(())
+}
+
+#[query(name = "getStake")]
+pub fn get_stake(user: Principal) -> StakeInfo {
+    STATE.with(|state| {
+        let state = state.borrow();
+        StakeInfo {
+            amount: state.stakes.get(&user).map(|history| staking::current_stake(history)).unwrap_or_else(|| Nat::from(0u64)),
+            locked_until: state.stake_locks.get(&user).cloned().unwrap_or_else(|| Nat::from(0u64)),
+        }
+    })
+}
+
+#[update(name = "delegate")]
+pub fn delegate(to: Principal, risk_type: Option<RiskType>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if to == caller {
+        return Err("Cannot delegate to yourself".to_string());
+    }
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        
+        delegation::set(state.delegations.entry(caller).or_default(), to, risk_type);
+        Ok(())
+    })
+}
+
+#[update(name = "revokeDelegation")]
+pub fn revoke_delegation(risk_type: Option<RiskType>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let delegations = state.delegations.get_mut(&caller).ok_or("No delegation found".to_string())?;
+        let before = delegations.len();
+        delegations.retain(|delegation| delegation.risk_type != risk_type);
+        if delegations.len() == before {
+            return Err("No delegation found".to_string());
+        }
+        Ok(())
+    })
+}
+
+#[query(name = "getDelegations")]
+pub fn get_delegations(user: Principal) -> Vec<Delegation> {
+    STATE.with(|state| state.borrow().delegations.get(&user).cloned().unwrap_or_default())
+}
+
+#[query(name = "getDelegators")]
+pub fn get_delegators(delegate: Principal, risk_type: Option<RiskType>) -> Vec<Principal> {
+    STATE.with(|state| {
+        state.borrow().delegations
+            .iter()
+            .filter(|(_, delegations)| delegation::resolve(delegations, risk_type.as_ref()) == Some(delegate))
+            .map(|(delegator, _)| *delegator)
+            .collect()
+    })
+}
+
+// Only BQ staked in governance carries weight, read at the proposal snapshot. Tokens merely held can be
+// moved between principals, so they never vote. Stake withdrawn since the snapshot no longer counts either,
+// otherwise a staker could unstake before voting and still vote with tokens that are no longer locked.
+fn voting_power(state: &State, user: &Principal, snapshot_at: &Nat) -> Nat {
+    state.stakes
+        .get(user)
+        .map(|history| staking::stake_at(history, snapshot_at).min(staking::current_stake(history)))
+        .unwrap_or_else(|| Nat::from(0u64))
+}
+
+fn has_voted(state: &State, proposal_id: &Nat, user: &Principal) -> bool {
+    state.voters
+        .get(proposal_id)
+        .and_then(|v| v.get(user))
+        .map(|v| v.voted)
+        .unwrap_or(false)
+}
+
+#[update(name = "vote")]
+pub fn vote(proposal_id: Nat, in_favor: bool) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        ensure_configured(&state)?;
+        let mut proposal = find_proposal(&proposal_id)?;
+
+        if has_voted(&state, &proposal_id, &caller) {
+            return Err("Already voted".to_string());
+        }
+        if proposal.status != ProposalStatus::Pending {
+            return Err("Proposal is not open for voting".to_string());
+        }
+        if Nat::from(ic_cdk::api::time() / 1_000_000_000) >= proposal.deadline {
+            return Err("Voting period elapsed".to_string());
+        }
+
+        // Weights are read at the snapshot, so stake added after voting opened carries no extra weight.
+        let risk_type = delegation::scope(&proposal);
+        let own_weight = voting_power(&state, &caller, &proposal.snapshot_at);
+        let delegated: Vec<(Principal, Nat)> = state.delegations
+            .iter()
+            .filter(|(delegator, delegations)| {
+                delegation::resolve(delegations, risk_type) == Some(caller) && !has_voted(&state, &proposal_id, delegator)
+            })
+            .map(|(delegator, _)| (*delegator, voting_power(&state, delegator, &proposal.snapshot_at)))
+            .filter(|(_, weight)| *weight > Nat::from(0u64))
+            .collect();
+
+        let voter_weight = delegated.iter().fold(own_weight, |total, (_, weight)| total + weight.clone());
+        if voter_weight == Nat::from(0u64) {
+            return Err("No governance tokens staked at proposal start".to_string());
+        }
+
+        // Staked tokens stay locked until the vote closes so they cannot be withdrawn and reused.
+        for user in std::iter::once(caller).chain(delegated.iter().map(|(delegator, _)| *delegator)) {
+            let lock = state.stake_locks.entry(user).or_insert(Nat::from(0u64));
+            if *lock < proposal.deadline {
+                *lock = proposal.deadline.clone();
+            }
+        }
+
         if in_favor {
             proposal.votes_for += voter_weight.clone();
             proposal.voters_for.push(caller);
@@ -120,15 +700,24 @@ pub async fn vote(proposal_id: Nat, in_favor: bool) -> Result<(), String> {
             proposal.voters_against.push(caller);
         }
 
-        state.proposals.insert(proposal_id.clone(), proposal);
-        state.voters
-            .entry(proposal_id.clone())
-            .or_default()
-            .insert(caller, Voter {
+        save_proposal(proposal);
+        let voters = state.voters.entry(proposal_id.clone()).or_default();
+        for (delegator, weight) in delegated.iter() {
+            voters.insert(*delegator, Voter {
                 voted: true,
                 vote: in_favor,
-                weight: voter_weight,
+                weight: weight.clone(),
+                delegated_to: Some(caller),
+                delegators: vec![],
             });
+        }
+        voters.insert(caller, Voter {
+            voted: true,
+            vote: in_favor,
+            weight: voter_weight,
+            delegated_to: None,
+            delegators: delegated.into_iter().map(|(delegator, _)| delegator).collect(),
+        });
 
         Ok(())
     })
@@ -137,40 +726,47 @@ pub async fn vote(proposal_id: Nat, in_favor: bool) -> Result<(), String> {
 #[update(name = "executeProposal")]
 pub async fn execute_proposal(proposal_id: Nat) -> Result<(), String> {
     let caller = ic_cdk::caller();
-    let (bqbtc_canister, reward_amount) = STATE.with(|state| {
-        let state = state.borrow();
-        if !state.is_admin.get(&caller).cloned().unwrap_or(false) {
-            return Err("Not authorized".to_string())
+    STATE.with(|state| {
+        if !has_role(&state.borrow(), Role::Admin, &caller) {
+            return Err("Not authorized".to_string());
         }
-        let reward = state.reward_amount.clone();
-        Ok((state.bqbtc_contract.unwrap(), reward))
+        Ok(())
     })?;
 
-    let win_voters = STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        let proposal = state.proposals.get_mut(&proposal_id).ok_or("Proposal not found".to_string()).unwrap();
-
-        let mut win_voters = vec![];
+    finalize_proposal(proposal_id).await
+}
 
-        if proposal.status == ProposalStatus::Pending && ic_cdk::api::time() / 1_000_000_000 > proposal.deadline && !proposal.executed {
-            proposal.executed = true;
-            proposal.timeleft = Nat::from(0u64);
+async fn finalize_proposal(proposal_id: Nat) -> Result<(), String> {
+    let (bqbtc_canister, rewards, action) = STATE.with(|state| {
+        let state = state.borrow();
+        let bqbtc_canister = state.bqbtc_contract.ok_or("bqBTC canister address not set".to_string())?;
+        let reward_amount = state.reward_amount.clone();
+        let mut proposal = find_proposal(&proposal_id)?;
+        let win_voters = lifecycle::close_voting(&mut proposal, ic_cdk::api::time() / 1_000_000_000)?;
+        // Approved claims wait out the timelock like any other privileged change before they become payable.
+        let action = match (&proposal.status, &proposal.kind) {
+            (ProposalStatus::Approved, ProposalKind::Action(action)) => Some(action.clone()),
+            (ProposalStatus::Approved, ProposalKind::Claim) => Some(GovernanceAction::ReleaseClaimPayout { proposal_id: proposal_id.clone() }),
+            _ => None,
+        };
+        save_proposal(proposal);
 
+        let no_voters = HashMap::new();
+        let voters = state.voters.get(&proposal_id).unwrap_or(&no_voters);
+        let rewards: Vec<(Principal, Nat)> = win_voters
+            .into_iter()
+            .flat_map(|voter| delegation::split_reward(&reward_amount, voter, voters))
+            .collect();
+        Ok::<(Principal, Vec<(Principal, Nat)>, Option<GovernanceAction>), String>((bqbtc_canister, rewards, action))
+    })?;
 
-            if proposal.votes_for > proposal.votes_against {
-                proposal.status = ProposalStatus::Approved;
-                win_voters = proposal.voters_for.clone();
-            } else {
-                proposal.status = ProposalStatus::Rejected;
-                win_voters = proposal.voters_against.clone();
-            }
-        }
-        win_voters
-    });
+    if let Some(action) = action {
+        enqueue_action(action, Some(proposal_id.clone()), ic_cdk::id());
+    }
 
-    for voter in win_voters.iter() {
-        let reward_result: Result<(), _> = call(bqbtc_canister, "mint", (*voter, reward_amount.clone())).await;
-        if reward_result.is_err() {
+    for (voter, amount) in rewards.into_iter() {
+        let reward_result: Result<(TxReceipt,), _> = call(bqbtc_canister, "mint", (voter, amount)).await;
+        if !matches!(reward_result, Ok((Ok(_),))) {
             ic_cdk::println!("Failed to mint reward for voter: {:?}", voter);
         }
     }
@@ -178,35 +774,152 @@ pub async fn execute_proposal(proposal_id: Nat) -> Result<(), String> {
     Ok(())
 }
 
-#[update(name = "updateProposalStatusToClaimed")]
-pub async fn update_proposal_to_claimed(proposal_id: Nat) -> Result<(), String> {
-    STATE.with(| state | {
-        let mut state = state.borrow_mut();
-        let proposal = state.proposals.get_mut(&proposal_id).ok_or_else(|| "error getting proposal").unwrap();
-        proposal.status = ProposalStatus::Claimed;
+// Applies an approved parameter change. Only the calls listed in GovernanceAction can be made this way.
+async fn execute_action(action: GovernanceAction) -> Result<(), String> {
+    match action {
+        GovernanceAction::UpdatePool { pool_id, apy, min_period } => {
+            let pool_contract = STATE.with(|state| state.borrow().pool_contract.ok_or("Pool canister address not set".to_string()))?;
+            let result: Result<(Result<(), String>,), _> = call(pool_contract, "updatePool", (pool_id, apy, min_period)).await;
+            result.map_err(|_| "Failed to call updatePool".to_string())?.0
+        },
+        GovernanceAction::DeactivatePool { pool_id } => {
+            let pool_contract = STATE.with(|state| state.borrow().pool_contract.ok_or("Pool canister address not set".to_string()))?;
+            let result: Result<(Result<(), String>,), _> = call(pool_contract, "deactivatePool", (pool_id,)).await;
+            result.map_err(|_| "Failed to call deactivatePool".to_string())?.0
+        },
+        GovernanceAction::SetVotingDuration { seconds } => {
+            STATE.with(|state| state.borrow_mut().voting_duration = seconds);
+            Ok(())
+        },
+        GovernanceAction::UpdateRewardAmount { amount } => {
+            STATE.with(|state| state.borrow_mut().reward_amount = amount);
+            Ok(())
+        },
+        GovernanceAction::AddAdmin { admin } => {
+            STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                let State { roles: members, role_history, .. } = &mut *state;
+                roles::grant(members, role_history, Role::Admin, admin, ic_cdk::id(), ic_cdk::api::time() / 1_000_000_000)
+            })
+        },
+        GovernanceAction::SetTimelockDelay { seconds } => {
+            STATE.with(|state| state.borrow_mut().timelock_delay = seconds);
+            Ok(())
+        },
+        GovernanceAction::GrantRole { role, principal } => {
+            STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                let State { roles: members, role_history, .. } = &mut *state;
+                roles::grant(members, role_history, role, principal, ic_cdk::id(), ic_cdk::api::time() / 1_000_000_000)
+            })
+        },
+        GovernanceAction::RevokeRole { role, principal } => {
+            STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                let State { roles: members, role_history, .. } = &mut *state;
+                roles::revoke(members, role_history, role, principal, ic_cdk::id(), ic_cdk::api::time() / 1_000_000_000)
+            })
+        },
+        GovernanceAction::SetCanisterIds { governance_token, bqbtc, cover, pool } => {
+            STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                // Stakes are held in the current token, so swapping it would strand every staked balance.
+                let token_changes = governance_token.is_some() && governance_token != state.governance_token;
+                if token_changes && staking::current_stake(&state.total_staked) > Nat::from(0u64) {
+                    return Err("The governance token cannot change while stakes are outstanding".to_string());
+                }
+                state.governance_token = governance_token.or(state.governance_token);
+                state.bqbtc_contract = bqbtc.or(state.bqbtc_contract);
+                state.cover_contract = cover.or(state.cover_contract);
+                state.pool_contract = pool.or(state.pool_contract);
+                Ok(())
+            })
+        },
+        GovernanceAction::SetRegistry { registry } => {
+            STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                state.registry = Some(registry);
+                state.registry_version = 0;
+            });
+            schedule_registry_sync();
+            Ok(())
+        },
+        GovernanceAction::SetBitcoinCanister { canister, network } => {
+            STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                state.bitcoin_canister = Some(canister);
+                state.bitcoin_network = Some(network);
+            });
+            Ok(())
+        },
+        GovernanceAction::SetVotingRules { rules } => {
+            validate_voting_rules(&rules)?;
+            STATE.with(|state| state.borrow_mut().voting_rules = rules);
+            Ok(())
+        },
+        // Nothing to call: completing the queued entry moves the claim to Executed, which is what the
+        // claims canister waits for before paying.
+        GovernanceAction::ReleaseClaimPayout { proposal_id } => {
+            let proposal = find_proposal(&proposal_id)?;
+            if proposal.kind != ProposalKind::Claim || proposal.status != ProposalStatus::Approved {
+                return Err("Only approved claims can be released for payout".to_string());
+            }
+            Ok(())
+        },
+    }
+}
 
+// Checks the caller may queue a privileged change and queues it, returning the queue id.
+fn enqueue_admin_action(caller: Principal, action: GovernanceAction) -> Result<Nat, String> {
+    STATE.with(|state| {
+        if !has_role(&state.borrow(), Role::Admin, &caller) {
+            return Err("Not authorized".to_string());
+        }
         Ok(())
+    })?;
+
+    Ok(enqueue_action(action, None, caller))
+}
+
+#[update(name = "updateProposalStatusToClaimed")]
+pub async fn update_proposal_to_claimed(proposal_id: Nat) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !STATE.with(|state| has_role(&state.borrow(), Role::ClaimsExecutor, &caller)) {
+        return Err("Not authorized".to_string());
+    }
+    // A claims retry may repeat this call after a lost reply, so a proposal already claimed is left as is.
+    update_proposal(&proposal_id, |proposal| {
+        if proposal.status == ProposalStatus::Claimed {
+            return Ok(());
+        }
+        lifecycle::transition(proposal, ProposalStatus::Claimed, ic_cdk::api::time() / 1_000_000_000)
     })
 }
 
-#[update(name = "setVotingDuration")]
-pub async fn set_voting_duration(duration : u64) -> Result<(), String> {
-    STATE.with(| state | {
-        let mut state = state.borrow_mut();
-        state.voting_duration = duration;
+#[update(name = "cancelProposal")]
+pub async fn cancel_proposal(proposal_id: Nat) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let is_admin = STATE.with(|state| has_role(&state.borrow(), Role::Admin, &caller));
+    update_proposal(&proposal_id, |proposal| {
+        if proposal.proposal_param.user != caller && !is_admin {
+            return Err("Not authorized".to_string());
+        }
+        if !proposal.voters_for.is_empty() || !proposal.voters_against.is_empty() {
+            return Err("Cannot cancel a proposal that has votes".to_string());
+        }
 
-        Ok(())
+        lifecycle::transition(proposal, ProposalStatus::Cancelled, ic_cdk::api::time() / 1_000_000_000)
     })
 }
 
-#[update(name = "updateRewardAmount")]
-pub async fn update_reward_amount(reward : Nat) -> Result<(), String> {
-    STATE.with(| state | {
-        let mut state = state.borrow_mut();
-        state.reward_amount = reward;
+#[update(name = "setVotingDuration")]
+pub async fn set_voting_duration(duration : u64) -> Result<Nat, String> {
+    enqueue_admin_action(ic_cdk::caller(), GovernanceAction::SetVotingDuration { seconds: duration })
+}
 
-        Ok(())
-    })
+#[update(name = "updateRewardAmount")]
+pub async fn update_reward_amount(reward : Nat) -> Result<Nat, String> {
+    enqueue_admin_action(ic_cdk::caller(), GovernanceAction::UpdateRewardAmount { amount: reward })
 }
 
 #[query(name= "getProposalCount")]
@@ -219,44 +932,33 @@ pub async fn get_proposal_count() -> Result<Nat, String> {
 
 #[query(name = "getAllProposals")]
 pub async fn get_all_proposals() -> Result<Vec<Proposal>, String> {
-    STATE.with(| state | {
-        let state = state.borrow();
-        let proposals = state.proposals.values().cloned().collect();
-
-        Ok(proposals)
-    })
+    Ok(all_proposals())
 }
 
 #[query(name = "getActiveProposals")]
 pub async fn get_active_proposals() -> Result<Vec<Proposal>, String> {
-    STATE.with(|state| {
-        let state = state.borrow();
-        let current_time = Nat::from(ic_cdk::api::time() / 1_000_000_000);
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    let current_time = Nat::from(now);
 
-        let active_proposals: Vec<Proposal> = state.proposals
-            .values()
-            .filter(|proposal| proposal.deadline == Nat::from(0u64) || proposal.deadline > current_time && !proposal.executed)
-            .cloned()
-            .collect();
+    let active_proposals: Vec<Proposal> = all_proposals()
+        .into_iter()
+        .filter(|proposal| proposal.status == ProposalStatus::Pending && proposal.deadline > current_time)
+        .map(|proposal| Proposal { timeleft: lifecycle::time_left(&proposal, now), ..proposal })
+        .collect();
 
-        Ok(active_proposals)
-    })
+    Ok(active_proposals)
 }
 
 #[query(name = "getPastProposals")]
 pub async fn get_past_proposals() -> Result<Vec<Proposal>, String> {
-    STATE.with(|state| {
-        let state = state.borrow();
-        let current_time = Nat::from(ic_cdk::api::time() / 1_000_000_000);
+    let current_time = Nat::from(ic_cdk::api::time() / 1_000_000_000);
 
-        let active_proposals: Vec<Proposal> = state.proposals
-            .values()
-            .filter(|proposal| proposal.deadline == Nat::from(0u64) || proposal.deadline > current_time)
-            .cloned()
-            .collect();
+    let past_proposals: Vec<Proposal> = all_proposals()
+        .into_iter()
+        .filter(|proposal| proposal.status != ProposalStatus::Pending || proposal.deadline <= current_time)
+        .collect();
 
-        Ok(active_proposals)
-    })
+    Ok(past_proposals)
 }
 
 #[query(name = "getAllParticipants")]
@@ -281,22 +983,223 @@ pub async fn get_user_participation(user: Principal) -> Result<Nat, String> {
 
 #[query(name = "getProposalDetails")]
 pub fn get_proposal_details(proposal_id: Nat) -> Result<Proposal, String> {
+    find_proposal(&proposal_id)
+}
+
+// The address checked is the one the claimant committed to when filing, so evidence can only ever describe
+// the transaction the claim was filed for. Each lookup pays for up to MAX_UTXO_PAGES Bitcoin API calls,
+// hence the small per-proposal attempt budget, and evidence once verified is never replaced.
+#[update(name = "verifyClaimEvidence")]
+pub async fn verify_claim_evidence(proposal_id: Nat) -> Result<ClaimEvidence, String> {
+    let caller = ic_cdk::caller();
+    let (bitcoin_canister, network, tx_hash, btc_address) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let proposal = find_proposal(&proposal_id)?;
+        let is_privileged = has_role(&state, Role::Admin, &caller) || has_role(&state, Role::ClaimsExecutor, &caller);
+        if proposal.proposal_param.user != caller && !is_privileged {
+            return Err("Not authorized".to_string());
+        }
+        if proposal.kind != ProposalKind::Claim {
+            return Err("Only claim proposals carry evidence".to_string());
+        }
+        if proposal.status != ProposalStatus::Pending {
+            return Err("Proposal is not open for voting".to_string());
+        }
+        if proposal.evidence.is_some() {
+            return Err("Claim evidence has already been verified".to_string());
+        }
+
+        // Defaults to the management canister's Bitcoin API; a mock canister can be configured for local testing.
+        let bitcoin_canister = state.bitcoin_canister.unwrap_or_else(Principal::management_canister);
+        let network = state.bitcoin_network.unwrap_or(BitcoinNetwork::Mainnet);
+        let tx_hash = proposal.proposal_param.tx_hash.clone();
+        let btc_address = proposal.proposal_param.btc_address.clone();
+
+        // Counted before the lookup so concurrent calls draw on the same budget.
+        let attempts = state.evidence_attempts.entry(proposal_id.clone()).or_insert(0);
+        if *attempts >= MAX_EVIDENCE_ATTEMPTS {
+            return Err("Evidence verification attempts exhausted for this proposal".to_string());
+        }
+        *attempts += 1;
+        Ok::<(Principal, BitcoinNetwork, String, String), String>((bitcoin_canister, network, tx_hash, btc_address))
+    })?;
+
+    let txid = evidence::parse_txid(&tx_hash)?;
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    let claim_evidence = evidence::fetch(bitcoin_canister, network, btc_address, &txid, tx_hash, now).await?;
+
+    update_proposal(&proposal_id, |proposal| {
+        if proposal.evidence.is_some() {
+            return Err("Claim evidence has already been verified".to_string());
+        }
+        proposal.evidence = Some(claim_evidence.clone());
+        Ok(claim_evidence)
+    })
+}
+
+#[update(name = "setCanisterIds")]
+pub fn set_canister_ids(ids: CanisterIds) -> Result<Nat, String> {
+    enqueue_admin_action(ic_cdk::caller(), GovernanceAction::SetCanisterIds {
+        governance_token: ids.governance_token,
+        bqbtc: ids.bqbtc,
+        cover: ids.cover,
+        pool: ids.pool,
+    })
+}
+
+#[query(name = "getCanisterIds")]
+pub fn get_canister_ids() -> CanisterIds {
     STATE.with(|state| {
         let state = state.borrow();
-        state.proposals.get(&proposal_id).cloned().ok_or("Proposal not found".to_string())
+        CanisterIds {
+            governance_token: state.governance_token,
+            bqbtc: state.bqbtc_contract,
+            cover: state.cover_contract,
+            pool: state.pool_contract,
+        }
     })
 }
 
-#[update(name = "addAdmin")]
-pub fn add_admin(new_admin: Principal) -> Result<(), String> {
+// Queues the registry's canister set behind the timelock when it is newer than the version this canister
+// last saw. Only ids that differ are queued, and the token is left alone while stakes are outstanding.
+async fn sync_registry() -> Result<u64, String> {
+    let registry = STATE.with(|state| state.borrow().registry.ok_or("Registry canister not set".to_string()))?;
+    let result: Result<(ProtocolCanisters,), _> = call(registry, "getCanisters", ()).await;
+    let (canisters,) = result.map_err(|_| "Failed to read the registry".to_string())?;
+
+    let (action, version) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if canisters.version <= state.registry_version {
+            return (None, state.registry_version);
+        }
+        state.registry_version = canisters.version;
+
+        let changed = |new: Option<Principal>, current: Option<Principal>| new.filter(|id| Some(*id) != current);
+        let mut governance_token = changed(canisters.bqtoken, state.governance_token);
+        if governance_token.is_some() && staking::current_stake(&state.total_staked) > Nat::from(0u64) {
+            ic_cdk::println!("Ignoring the registry's governance token while stakes are outstanding");
+            governance_token = None;
+        }
+        let bqbtc = changed(canisters.bqbtc, state.bqbtc_contract);
+        let cover = changed(canisters.cover, state.cover_contract);
+        let pool = changed(canisters.pool, state.pool_contract);
+
+        let action = if governance_token.is_none() && bqbtc.is_none() && cover.is_none() && pool.is_none() {
+            None
+        } else {
+            Some(GovernanceAction::SetCanisterIds { governance_token, bqbtc, cover, pool })
+        };
+        (action, state.registry_version)
+    });
+
+    if let Some(action) = action {
+        enqueue_action(action, None, ic_cdk::id());
+    }
+    Ok(version)
+}
+
+// Inter-canister calls are not allowed during upgrade hooks, so the sync runs from a one-off timer.
+fn schedule_registry_sync() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(async {
+        let _ = sync_registry().await;
+    }));
+}
+
+#[update(name = "setRegistry")]
+pub fn set_registry(registry: Principal) -> Result<Nat, String> {
+    enqueue_admin_action(ic_cdk::caller(), GovernanceAction::SetRegistry { registry })
+}
+
+#[update(name = "syncRegistry")]
+pub async fn sync_registry_now() -> Result<u64, String> {
     let caller = ic_cdk::caller();
+    if !STATE.with(|state| has_role(&state.borrow(), Role::Admin, &caller)) {
+        return Err("Not authorized".to_string());
+    }
+    sync_registry().await
+}
+
+#[query(name = "getRegistry")]
+pub fn get_registry() -> (Option<Principal>, u64) {
     STATE.with(|state| {
-        if !state.borrow().is_admin.get(&caller).cloned().unwrap_or(false) {
-            return Err("Not authorized".to_string());
-        }
-        state.borrow_mut().is_admin.insert(new_admin, true);
-        Ok(())
+        let state = state.borrow();
+        (state.registry, state.registry_version)
+    })
+}
+
+#[update(name = "setBitcoinCanister")]
+pub fn set_bitcoin_canister(bitcoin_canister: Principal, network: BitcoinNetwork) -> Result<Nat, String> {
+    enqueue_admin_action(ic_cdk::caller(), GovernanceAction::SetBitcoinCanister { canister: bitcoin_canister, network })
+}
+
+fn validate_voting_rules(rules: &VotingRules) -> Result<(), String> {
+    if rules.quorum_bps > 10_000 || rules.approval_threshold_bps > 10_000 || rules.large_claim_threshold_bps > 10_000 {
+        return Err("Voting rules must be expressed in basis points".to_string());
+    }
+    if rules.large_claim_threshold_bps < rules.approval_threshold_bps {
+        return Err("Large claim threshold cannot be lower than the approval threshold".to_string());
+    }
+    Ok(())
+}
+
+#[update(name = "setVotingRules")]
+pub fn set_voting_rules(rules: VotingRules) -> Result<Nat, String> {
+    validate_voting_rules(&rules)?;
+    enqueue_admin_action(ic_cdk::caller(), GovernanceAction::SetVotingRules { rules })
+}
+
+#[query(name = "getVotingRules")]
+pub fn get_voting_rules() -> VotingRules {
+    STATE.with(|state| state.borrow().voting_rules.clone())
+}
+
+#[update(name = "addAdmin")]
+pub fn add_admin(new_admin: Principal) -> Result<Nat, String> {
+    enqueue_admin_action(ic_cdk::caller(), GovernanceAction::AddAdmin { admin: new_admin })
+}
+
+#[update(name = "removeAdmin")]
+pub fn remove_admin(admin: Principal) -> Result<Nat, String> {
+    revoke_role(Role::Admin, admin)
+}
+
+// Role changes wait out the timelock so a guardian can cancel one made with a compromised admin key.
+#[update(name = "grantRole")]
+pub fn grant_role(role: Role, principal: Principal) -> Result<Nat, String> {
+    if role == Role::Admin {
+        return Err("Admins must be added through addAdmin".to_string());
+    }
+    enqueue_admin_action(ic_cdk::caller(), GovernanceAction::GrantRole { role, principal })
+}
+
+#[update(name = "revokeRole")]
+pub fn revoke_role(role: Role, principal: Principal) -> Result<Nat, String> {
+    let has_member = STATE.with(|state| has_role(&state.borrow(), role, &principal));
+    if !has_member {
+        return Err(format!("Principal does not have the {:?} role", role));
+    }
+    enqueue_admin_action(ic_cdk::caller(), GovernanceAction::RevokeRole { role, principal })
+}
+
+#[query(name = "getRoles")]
+pub fn get_roles(principal: Principal) -> Vec<Role> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        [Role::Admin, Role::Guardian, Role::ClaimsExecutor]
+            .into_iter()
+            .filter(|role| has_role(&state, *role, &principal))
+            .collect()
     })
 }
 
+#[query(name = "getRoleMembers")]
+pub fn get_role_members(role: Role) -> Vec<Principal> {
+    STATE.with(|state| state.borrow().roles.get(&role).cloned().unwrap_or_default())
+}
+
+#[query(name = "getRoleHistory")]
+pub fn get_role_history() -> Vec<RoleChange> {
+    STATE.with(|state| state.borrow().role_history.clone())
+}
+
 ic_cdk::export_candid!();
\ No newline at end of file
@@ -0,0 +1,219 @@
+use candid::{Nat, Principal};
+
+use crate::types::{Proposal, ProposalStatus, StatusChange, VotingRules};
+
+const BASIS_POINTS: u64 = 10_000;
+
+fn is_valid_transition(from: &ProposalStatus, to: &ProposalStatus) -> bool {
+    use ProposalStatus::*;
+
+    matches!(
+        (from, to),
+        (Submitted, Pending)
+            | (Submitted, Cancelled)
+            | (Pending, Approved)
+            | (Pending, Rejected)
+            | (Pending, Expired)
+            | (Pending, QuorumNotMet)
+            | (Pending, Cancelled)
+            | (Approved, Executed)
+            | (Approved, Cancelled)
+            | (Executed, Claimed)
+    )
+}
+
+pub fn transition(proposal: &mut Proposal, to: ProposalStatus, now: u64) -> Result<(), String> {
+    if !is_valid_transition(&proposal.status, &to) {
+        return Err(format!("Invalid proposal transition from {:?} to {:?}", proposal.status, to));
+    }
+
+    proposal.status = to.clone();
+    proposal.status_history.push(StatusChange { status: to, timestamp: Nat::from(now) });
+    Ok(())
+}
+
+pub fn open_voting(proposal: &mut Proposal, voting_duration: u64, now: u64) -> Result<(), String> {
+    transition(proposal, ProposalStatus::Pending, now)?;
+    proposal.deadline = Nat::from(now + voting_duration);
+    proposal.timeleft = Nat::from(voting_duration);
+    proposal.snapshot_at = Nat::from(now);
+    Ok(())
+}
+
+// Fixes the quorum and approval threshold a proposal is judged by, so later rule changes do not affect open votes.
+// Parameter changes have no pool TVL to compare against and always need the supermajority used for large claims.
+pub fn apply_rules(proposal: &mut Proposal, rules: &VotingRules, staked_supply: Nat, pool_tvl: Option<Nat>) {
+    proposal.quorum = staked_supply * Nat::from(rules.quorum_bps) / Nat::from(BASIS_POINTS);
+
+    let large_claim = match pool_tvl {
+        Some(pool_tvl) => {
+            proposal.proposal_param.claim_amount.clone() * Nat::from(BASIS_POINTS)
+                >= pool_tvl * Nat::from(rules.large_claim_tvl_bps)
+        },
+        None => true,
+    };
+    let threshold_bps = if large_claim { rules.large_claim_threshold_bps } else { rules.approval_threshold_bps };
+    proposal.approval_threshold_bps = Nat::from(threshold_bps);
+}
+
+// Closes a proposal whose voting window has elapsed and returns the principals on the winning side.
+pub fn close_voting(proposal: &mut Proposal, now: u64) -> Result<Vec<Principal>, String> {
+    if proposal.status != ProposalStatus::Pending {
+        return Err("Proposal is not open for voting".to_string());
+    }
+    if Nat::from(now) <= proposal.deadline {
+        return Err("Voting period has not ended".to_string());
+    }
+
+    let zero = Nat::from(0u64);
+    let total_votes = proposal.votes_for.clone() + proposal.votes_against.clone();
+    let (status, winners) = if total_votes == zero {
+        (ProposalStatus::Expired, vec![])
+    } else if total_votes < proposal.quorum {
+        (ProposalStatus::QuorumNotMet, vec![])
+    } else if proposal.votes_for.clone() * Nat::from(BASIS_POINTS) > total_votes * proposal.approval_threshold_bps.clone() {
+        (ProposalStatus::Approved, proposal.voters_for.clone())
+    } else {
+        (ProposalStatus::Rejected, proposal.voters_against.clone())
+    };
+
+    transition(proposal, status, now)?;
+    proposal.executed = true;
+    proposal.timeleft = zero;
+    Ok(winners)
+}
+
+pub fn time_left(proposal: &Proposal, now: u64) -> Nat {
+    let now = Nat::from(now);
+    if proposal.status == ProposalStatus::Pending && proposal.deadline > now {
+        proposal.deadline.clone() - now
+    } else {
+        Nat::from(0u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{nat, proposal, rules};
+
+    const VOTING_DURATION: u64 = 100;
+
+    fn open_proposal(votes_for: u64, votes_against: u64, quorum: u64) -> Proposal {
+        let mut proposal = proposal();
+        open_voting(&mut proposal, VOTING_DURATION, 10).unwrap();
+        proposal.votes_for = nat(votes_for);
+        proposal.votes_against = nat(votes_against);
+        proposal.voters_for = vec![Principal::anonymous()];
+        proposal.voters_against = vec![Principal::management_canister()];
+        proposal.quorum = nat(quorum);
+        proposal
+    }
+
+    #[test]
+    fn open_voting_starts_the_window_and_records_the_snapshot() {
+        let mut proposal = proposal();
+        open_voting(&mut proposal, VOTING_DURATION, 10).unwrap();
+
+        assert_eq!(proposal.status, ProposalStatus::Pending);
+        assert_eq!(proposal.deadline, nat(110));
+        assert_eq!(proposal.timeleft, nat(VOTING_DURATION));
+        assert_eq!(proposal.snapshot_at, nat(10));
+        assert_eq!(proposal.status_history.len(), 1);
+        assert!(open_voting(&mut proposal, VOTING_DURATION, 20).is_err());
+    }
+
+    #[test]
+    fn invalid_transitions_leave_the_proposal_untouched() {
+        let mut proposal = proposal();
+        assert!(transition(&mut proposal, ProposalStatus::Executed, 10).is_err());
+        assert_eq!(proposal.status, ProposalStatus::Submitted);
+        assert!(proposal.status_history.is_empty());
+    }
+
+    #[test]
+    fn approved_proposals_are_executed_then_claimed() {
+        let mut proposal = open_proposal(80, 20, 50);
+        close_voting(&mut proposal, 111).unwrap();
+        transition(&mut proposal, ProposalStatus::Executed, 120).unwrap();
+        transition(&mut proposal, ProposalStatus::Claimed, 130).unwrap();
+
+        let history: Vec<ProposalStatus> = proposal.status_history.iter().map(|change| change.status.clone()).collect();
+        assert_eq!(
+            history,
+            vec![ProposalStatus::Pending, ProposalStatus::Approved, ProposalStatus::Executed, ProposalStatus::Claimed]
+        );
+        assert!(transition(&mut proposal, ProposalStatus::Cancelled, 140).is_err());
+    }
+
+    #[test]
+    fn closed_proposals_cannot_be_reopened_or_approved() {
+        let mut proposal = open_proposal(20, 80, 50);
+        close_voting(&mut proposal, 111).unwrap();
+        assert_eq!(proposal.status, ProposalStatus::Rejected);
+        assert!(transition(&mut proposal, ProposalStatus::Approved, 120).is_err());
+        assert!(transition(&mut proposal, ProposalStatus::Pending, 120).is_err());
+    }
+
+    #[test]
+    fn close_voting_waits_for_the_deadline() {
+        let mut proposal = open_proposal(80, 20, 50);
+        assert!(close_voting(&mut proposal, 110).is_err());
+        assert_eq!(proposal.status, ProposalStatus::Pending);
+        assert_eq!(time_left(&proposal, 60), nat(50));
+        assert_eq!(time_left(&proposal, 200), nat(0));
+    }
+
+    #[test]
+    fn close_voting_decides_by_turnout_and_threshold() {
+        let mut expired = open_proposal(0, 0, 50);
+        assert!(close_voting(&mut expired, 111).unwrap().is_empty());
+        assert_eq!(expired.status, ProposalStatus::Expired);
+
+        let mut short = open_proposal(30, 10, 50);
+        assert!(close_voting(&mut short, 111).unwrap().is_empty());
+        assert_eq!(short.status, ProposalStatus::QuorumNotMet);
+
+        let mut approved = open_proposal(80, 20, 50);
+        assert_eq!(close_voting(&mut approved, 111).unwrap(), vec![Principal::anonymous()]);
+        assert_eq!(approved.status, ProposalStatus::Approved);
+        assert!(approved.executed);
+        assert_eq!(approved.timeleft, nat(0));
+
+        // Approval must exceed the threshold; a tie at exactly half is rejected.
+        let mut tied = open_proposal(50, 50, 50);
+        assert_eq!(close_voting(&mut tied, 111).unwrap(), vec![Principal::management_canister()]);
+        assert_eq!(tied.status, ProposalStatus::Rejected);
+    }
+
+    #[test]
+    fn cancellation_is_only_possible_before_execution() {
+        let mut pending = open_proposal(0, 0, 0);
+        transition(&mut pending, ProposalStatus::Cancelled, 20).unwrap();
+
+        let mut approved = open_proposal(80, 20, 50);
+        close_voting(&mut approved, 111).unwrap();
+        transition(&mut approved, ProposalStatus::Cancelled, 120).unwrap();
+
+        let mut executed = open_proposal(80, 20, 50);
+        close_voting(&mut executed, 111).unwrap();
+        transition(&mut executed, ProposalStatus::Executed, 120).unwrap();
+        assert!(transition(&mut executed, ProposalStatus::Cancelled, 130).is_err());
+    }
+
+    #[test]
+    fn apply_rules_scales_the_threshold_with_claim_size() {
+        let mut small = proposal();
+        apply_rules(&mut small, &rules(), nat(1_000), Some(nat(10_000)));
+        assert_eq!(small.quorum, nat(200));
+        assert_eq!(small.approval_threshold_bps, nat(5_000));
+
+        let mut large = proposal();
+        apply_rules(&mut large, &rules(), nat(1_000), Some(nat(1_000)));
+        assert_eq!(large.approval_threshold_bps, nat(6_667));
+
+        let mut action = proposal();
+        apply_rules(&mut action, &rules(), nat(1_000), None);
+        assert_eq!(action.approval_threshold_bps, nat(6_667));
+    }
+}
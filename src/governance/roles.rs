@@ -0,0 +1,50 @@
+use candid::{Nat, Principal};
+use std::collections::HashMap;
+
+use crate::types::{Role, RoleChange};
+
+pub fn has_role(members: &HashMap<Role, Vec<Principal>>, role: Role, principal: &Principal) -> bool {
+    members.get(&role).map(|principals| principals.contains(principal)).unwrap_or(false)
+}
+
+// Every grant and revocation is appended to `history`, which is never pruned.
+pub fn grant(
+    members: &mut HashMap<Role, Vec<Principal>>,
+    history: &mut Vec<RoleChange>,
+    role: Role,
+    principal: Principal,
+    changed_by: Principal,
+    now: u64,
+) -> Result<(), String> {
+    let principals = members.entry(role).or_default();
+    if principals.contains(&principal) {
+        return Err(format!("Principal already has the {:?} role", role));
+    }
+    principals.push(principal);
+    history.push(RoleChange { role, principal, granted: true, changed_by, timestamp: Nat::from(now) });
+    Ok(())
+}
+
+pub fn revoke(
+    members: &mut HashMap<Role, Vec<Principal>>,
+    history: &mut Vec<RoleChange>,
+    role: Role,
+    principal: Principal,
+    changed_by: Principal,
+    now: u64,
+) -> Result<(), String> {
+    let principals = members.entry(role).or_default();
+    if !principals.contains(&principal) {
+        return Err(format!("Principal does not have the {:?} role", role));
+    }
+    if role == Role::Admin && principals.len() == 1 {
+        return Err("Cannot remove the last admin".to_string());
+    }
+    // Without a guardian nothing could cancel a queued change, including the one removing the guardian.
+    if role == Role::Guardian && principals.len() == 1 {
+        return Err("Cannot remove the last guardian".to_string());
+    }
+    principals.retain(|member| *member != principal);
+    history.push(RoleChange { role, principal, granted: false, changed_by, timestamp: Nat::from(now) });
+    Ok(())
+}
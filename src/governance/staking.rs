@@ -0,0 +1,25 @@
+use candid::Nat;
+
+use crate::types::StakeCheckpoint;
+
+pub fn current_stake(history: &[StakeCheckpoint]) -> Nat {
+    history.last().map(|checkpoint| checkpoint.amount.clone()).unwrap_or_else(|| Nat::from(0u64))
+}
+
+// Stake held strictly before `timestamp`, so tokens staked in the same second a proposal opens carry no weight.
+pub fn stake_at(history: &[StakeCheckpoint], timestamp: &Nat) -> Nat {
+    history
+        .iter()
+        .rev()
+        .find(|checkpoint| checkpoint.timestamp < *timestamp)
+        .map(|checkpoint| checkpoint.amount.clone())
+        .unwrap_or_else(|| Nat::from(0u64))
+}
+
+pub fn checkpoint(history: &mut Vec<StakeCheckpoint>, amount: Nat, now: u64) {
+    let timestamp = Nat::from(now);
+    match history.last_mut() {
+        Some(last) if last.timestamp == timestamp => last.amount = amount,
+        _ => history.push(StakeCheckpoint { timestamp, amount }),
+    }
+}
@@ -0,0 +1,50 @@
+// Fixtures shared by the governance canister's unit tests.
+use candid::{Nat, Principal};
+
+use crate::types::{Proposal, ProposalKind, ProposalParam, ProposalStatus, RiskType, VotingRules};
+
+pub fn nat(value: u64) -> Nat {
+    Nat::from(value)
+}
+
+// A freshly submitted claim for 100 bqBTC out of pool 1, judged by simple majority.
+pub fn proposal() -> Proposal {
+    Proposal {
+        id: nat(1),
+        votes_for: nat(0),
+        votes_against: nat(0),
+        created_at: nat(0),
+        deadline: nat(0),
+        timeleft: nat(0),
+        status: ProposalStatus::Submitted,
+        executed: false,
+        proposal_param: ProposalParam {
+            user: Principal::anonymous(),
+            risk_type: RiskType::Slashing,
+            cover_id: nat(1),
+            tx_hash: String::new(),
+            btc_address: String::new(),
+            description: String::new(),
+            pool_id: nat(1),
+            claim_amount: nat(100),
+        },
+        voters_for: vec![],
+        voters_against: vec![],
+        status_history: vec![],
+        snapshot_at: nat(0),
+        quorum: nat(0),
+        approval_threshold_bps: nat(5_000),
+        evidence: None,
+        kind: ProposalKind::Claim,
+        execution_error: None,
+    }
+}
+
+pub fn rules() -> VotingRules {
+    VotingRules {
+        quorum_bps: 2_000,
+        approval_threshold_bps: 5_000,
+        large_claim_threshold_bps: 6_667,
+        large_claim_tvl_bps: 1_000,
+    }
+}
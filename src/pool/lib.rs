@@ -1,16 +1,29 @@
 use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_cdk_macros::*;
+use ic_stable_structures::StableBTreeMap;
+use shared::stable::{self, Candid, Memory};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::time::Duration;
 use ic_cdk::api::call::call;
 
+mod rewards;
+mod shares;
+#[cfg(test)]
+mod testing;
 mod types;
-use types::{Cover, Proposal, ProposalStatus, RiskType, Pool, PoolInfo, Status, Deposit};
+use types::{Cover, RiskType, Pool, PoolInfo, ProtocolCanisters, Status, Deposit, TxReceipt};
 
 const ZER0 :u64 = 0;
+const DEPOSITS_MEMORY: u8 = 1;
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::default();
+
+    // Every deposit opens its own position, keyed by LP, pool and deposit id, so positions live in stable
+    // memory instead of being copied in and out of the heap on each upgrade.
+    static DEPOSITS: RefCell<StableBTreeMap<Candid<(Principal, Nat, Nat)>, Candid<Deposit>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(DEPOSITS_MEMORY)));
 }
 
 #[derive(CandidType, Deserialize, Default)]
@@ -21,9 +34,67 @@ struct State {
     bq_btc_address: Option<Principal>,
     cover_address: Option<Principal>,
     gov_address: Option<Principal>,
+    claims_address: Option<Principal>,
     participants: Vec<Principal>,
     participation: HashMap<Principal, Nat>,
-    pool_covers: HashMap<Nat, Vec<Cover>>
+    pool_covers: HashMap<Nat, Vec<Cover>>,
+    registry: Option<Principal>,
+    registry_version: u64,
+}
+
+// Layouts the state has been saved in across upgrades; post_upgrade migrates older ones forward.
+#[derive(CandidType, Deserialize)]
+enum StableState {
+    V1(State),
+}
+
+fn find_deposit(lp: Principal, pool_id: &Nat, deposit_id: &Nat) -> Option<Deposit> {
+    DEPOSITS.with(|deposits| deposits.borrow().get(&Candid((lp, pool_id.clone(), deposit_id.clone()))))
+        .map(|Candid(deposit)| deposit)
+}
+
+fn save_deposit(deposit: Deposit) {
+    DEPOSITS.with(|deposits| {
+        deposits.borrow_mut().insert(Candid((deposit.lp, deposit.pool_id.clone(), deposit.id.clone())), Candid(deposit))
+    });
+}
+
+fn deposits_of(lp: Principal) -> Vec<Deposit> {
+    DEPOSITS.with(|deposits| {
+        deposits.borrow()
+            .range(Candid((lp, Nat::from(ZER0), Nat::from(ZER0)))..)
+            .take_while(|(Candid((owner, _, _)), _)| *owner == lp)
+            .map(|(_, Candid(deposit))| deposit)
+            .collect()
+    })
+}
+
+#[derive(CandidType, Deserialize)]
+struct CanisterIds {
+    bqbtc: Option<Principal>,
+    cover: Option<Principal>,
+    governance: Option<Principal>,
+    claims: Option<Principal>,
+}
+
+// User-facing endpoints refuse to run until every peer canister has been wired up.
+fn ensure_configured(state: &State) -> Result<(), String> {
+    let missing: Vec<&str> = [
+        ("bqBTC", state.bq_btc_address),
+        ("cover", state.cover_address),
+        ("governance", state.gov_address),
+        ("claims", state.claims_address),
+    ]
+    .iter()
+    .filter(|(_, id)| id.is_none())
+    .map(|(name, _)| *name)
+    .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Pool canister is not configured: missing {}", missing.join(", ")))
+    }
 }
 
 #[init]
@@ -35,6 +106,26 @@ fn init(owner: Principal, bq_btc: Principal) {
     });
 }
 
+#[pre_upgrade]
+fn pre_upgrade() {
+    STATE.with(|state| stable::save_state(&StableState::V1(std::mem::take(&mut *state.borrow_mut()))));
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let saved = match stable::restore_state() {
+        Some(StableState::V1(saved)) => saved,
+        None => {
+            ic_cdk::println!("No saved state found, starting from an empty state");
+            State::default()
+        },
+    };
+    STATE.with(|state| *state.borrow_mut() = saved);
+    if STATE.with(|state| state.borrow().registry.is_some()) {
+        schedule_registry_sync();
+    }
+}
+
 #[update(name = "createPool")]
 async fn create_pool(risk_type: RiskType, pool_name: String, apy: Nat, min_period: Nat) -> Result<(), String> {
     let caller = ic_cdk::caller();
@@ -53,9 +144,14 @@ async fn create_pool(risk_type: RiskType, pool_name: String, apy: Nat, min_perio
             tcp: Nat::from(ZER0),
             is_active: true,
             percentage_split_balance: Nat::from(100u64),
-            deposits: HashMap::new(),
+            deposit_count: Nat::from(ZER0),
+            total_shares: Nat::from(ZER0),
+            reward_index: Nat::from(ZER0),
+            last_accrued_at: Nat::from(ic_cdk::api::time() / 1_000_000_000),
+            premium_reserve: Nat::from(ZER0),
         };
         state.pools.insert(pool_count.clone(), new_pool);
+        state.pool_covers.insert(pool_count.clone(), vec![]);
         state.pool_count = pool_count;
         Ok(())
     })
@@ -66,57 +162,29 @@ async fn update_pool(pool_id: Nat, apy: Nat, min_period: Nat) -> Result<(), Stri
     let caller = ic_cdk::caller();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if state.owner != Some(caller) {
-            return Err("Only owner can create pools".to_string());
+        if state.owner != Some(caller) && state.gov_address != Some(caller) {
+            return Err("Only owner or governance can update pools".to_string());
         }
 
         let pool = state.pools.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
+        rewards::accrue(pool, ic_cdk::api::time() / 1_000_000_000);
         pool.apy = apy;
         pool.min_period = min_period;
         Ok(())
     })
 }
 
-#[update(name = "reducePercentageSplit")]
-async fn reduce_percentage_split(pool_id: Nat, percentage_split: Nat) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        if state.cover_address != Some(caller) {
-            return Err("Only cover contract can create pools".to_string());
-        }
-
-        let pool = state.pools.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
-        pool.percentage_split_balance -= percentage_split;
-        Ok(())
-    })
-}
-
-#[update(name = "increasePercentageSplit")]
-async fn increase_percentage_split(pool_id: Nat, percentage_split: Nat) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        if state.cover_address != Some(caller) {
-            return Err("Only cover contract can create pools".to_string());
-        }
-
-        let pool = state.pools.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
-        pool.percentage_split_balance += percentage_split;
-        Ok(())
-    })
-}
-
 #[update(name = "deactivatePool")]
 async fn deactivate_pool(pool_id: Nat) -> Result<(), String> {
     let caller = ic_cdk::caller();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        if state.owner != Some(caller) {
-            return Err("Only owner can create pools".to_string());
+        if state.owner != Some(caller) && state.gov_address != Some(caller) {
+            return Err("Only owner or governance can deactivate pools".to_string());
         }
 
         let pool = state.pools.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
+        rewards::accrue(pool, ic_cdk::api::time() / 1_000_000_000);
         pool.is_active = false;
         Ok(())
     })
@@ -138,8 +206,45 @@ fn get_all_pools() -> Vec<(Nat, Pool)> {
     })
 }
 
+// Cover capacity is a percentage of the pool's TVL. Reserving it from `percentage_split_balance` in the same
+// message that registers the cover keeps the covers on a pool from promising more than 100% of its capital.
+#[update(name = "addPoolCover")]
+async fn add_pool_covers(pool_id: Nat, new_cover: Cover) -> Result<Nat, String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.cover_address != Some(caller) {
+            return Err("Only the cover contract can add covers".to_string());
+        }
+
+        let State { pools, pool_covers, .. } = &mut *state;
+        let pool = pools.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
+        let pool_covers = pool_covers.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
+        if !pool.is_active {
+            return Err("Pool is inactive".to_string());
+        }
+        if new_cover.risk_type != pool.risk_type {
+            return Err("Wrong pool, risk type must be the same!".to_string());
+        }
+        if pool_covers.iter().any(|cover| cover.id == new_cover.id || cover.cover_name == new_cover.cover_name) {
+            return Err("Cover Already exists!".to_string());
+        }
+        if new_cover.capacity == Nat::from(0u64) {
+            return Err("Invalid cover capacity".to_string());
+        }
+        if new_cover.capacity > pool.percentage_split_balance {
+            return Err("Cover capacity exceeds the pool's unallocated percentage".to_string());
+        }
+
+        pool.percentage_split_balance -= new_cover.capacity.clone();
+        pool_covers.push(new_cover);
+        Ok(pool.tvl.clone())
+    })
+}
+
+// Resizes a cover's reservation by the difference from the capacity it currently holds.
 #[update(name = "updatePoolCovers")]
-async fn update_pool_covers(pool_id: Nat, new_cover: Cover) -> Result<(), String> {
+async fn update_pool_covers(pool_id: Nat, new_cover: Cover) -> Result<Nat, String> {
     let caller = ic_cdk::caller();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
@@ -147,38 +252,61 @@ async fn update_pool_covers(pool_id: Nat, new_cover: Cover) -> Result<(), String
             return Err("Only the cover contract can update covers".to_string());
         }
 
-        let pool_covers = state.pool_covers.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
-        let mut found = false;
-
-        for cover in pool_covers.iter_mut() {
-            if cover.id == new_cover.id {
-                *cover = new_cover;
-                found = true;
-                break;
-            }
+        let State { pools, pool_covers, .. } = &mut *state;
+        let pool = pools.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
+        let pool_covers = pool_covers.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
+        if new_cover.risk_type != pool.risk_type {
+            return Err("Wrong pool, risk type must be the same!".to_string());
+        }
+        if pool_covers.iter().any(|cover| cover.id != new_cover.id && cover.cover_name == new_cover.cover_name) {
+            return Err("Cover Already exists!".to_string());
+        }
+        if new_cover.capacity == Nat::from(0u64) {
+            return Err("Invalid cover capacity".to_string());
+        }
+        if new_cover.cover_values.clone() * Nat::from(100u64) > pool.tvl.clone() * new_cover.capacity.clone() {
+            return Err("Capacity would not back the cover already sold".to_string());
         }
 
-        if !found {
-            return Err("Cover not found in pool".to_string());
+        let cover = pool_covers
+            .iter_mut()
+            .find(|cover| cover.id == new_cover.id)
+            .ok_or("Cover not found in pool".to_string())?;
+        if new_cover.capacity > cover.capacity {
+            let increase = new_cover.capacity.clone() - cover.capacity.clone();
+            if increase > pool.percentage_split_balance {
+                return Err("Cover capacity exceeds the pool's unallocated percentage".to_string());
+            }
+            pool.percentage_split_balance -= increase;
+        } else {
+            pool.percentage_split_balance += cover.capacity.clone() - new_cover.capacity.clone();
         }
 
-        Ok(())
+        *cover = new_cover;
+        Ok(pool.tvl.clone())
     })
 }
 
-#[update(name = "addPoolCover")]
-async fn add_pool_covers(pool_id: Nat, new_cover: Cover) -> Result<(), String> {
+// Returns a retired cover's capacity to the pool.
+#[update(name = "releasePoolCover")]
+async fn release_pool_cover(pool_id: Nat, cover_id: Nat) -> Result<(), String> {
     let caller = ic_cdk::caller();
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         if state.cover_address != Some(caller) {
-            return Err("Only the cover contract can add covers".to_string());
+            return Err("Only the cover contract can release covers".to_string());
         }
 
-        let pool_covers = state.pool_covers.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
-        
-        pool_covers.push(new_cover);
-        
+        let State { pools, pool_covers, .. } = &mut *state;
+        let pool = pools.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
+        let pool_covers = pool_covers.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
+        let index = pool_covers
+            .iter()
+            .position(|cover| cover.id == cover_id)
+            .ok_or("Cover not found in pool".to_string())?;
+
+        let cover = pool_covers.remove(index);
+        pool.percentage_split_balance += cover.capacity;
         Ok(())
     })
 }
@@ -196,34 +324,47 @@ async fn get_pool_covers(pool_id: Nat) -> Result<Vec<Cover>, String> {
 
 #[query(name = "getPoolsByAddress")]
 fn get_pools_by_address(user_address: Principal) -> Vec<PoolInfo> {
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    let deposits = deposits_of(user_address);
     STATE.with(|state| {
         let state = state.borrow();
-        state.pools.iter()
-            .filter_map(|(pool_id, pool)| {
-                pool.deposits.get(&user_address).map(|deposit| {
-                    PoolInfo {
-                        pool_name: pool.pool_name.clone(),
-                        pool_id: pool_id.clone(),
-                        daily_payout: deposit.daily_payout.clone(),
-                        deposit_amount: deposit.amount.clone(),
-                        apy: pool.apy.clone(),
-                        min_period: pool.min_period.clone(),
-                        tvl: pool.tvl.clone(),
-                        tcp: pool.tcp.clone(),
-                        is_active: pool.is_active,
-                        accrued_payout: deposit.accrued_payout.clone(),
-                    }
+        deposits.iter()
+            .filter_map(|deposit| {
+                let pool = state.pools.get(&deposit.pool_id)?;
+                let reward_index = rewards::index_at(pool, now);
+                let deposit_value = shares::to_amount(&deposit.shares, &pool.total_shares, &pool.tvl);
+                Some(PoolInfo {
+                    pool_name: pool.pool_name.clone(),
+                    pool_id: deposit.pool_id.clone(),
+                    deposit_id: deposit.id.clone(),
+                    daily_payout: rewards::daily_payout(&deposit_value, &pool.apy),
+                    deposit_amount: deposit.amount.clone(),
+                    shares: deposit.shares.clone(),
+                    deposit_value,
+                    apy: pool.apy.clone(),
+                    min_period: pool.min_period.clone(),
+                    tvl: pool.tvl.clone(),
+                    tcp: pool.tcp.clone(),
+                    is_active: pool.is_active,
+                    accrued_payout: rewards::pending(deposit, &reward_index),
                 })
             })
             .collect()
     })
 }
 
+// Redeems `shares` of a position for their current share of the pool's TVL.
 #[update(name = "withdraw")]
-async fn withdraw(pool_id: Nat, amount: Nat) -> Result<(), String> {
+async fn withdraw(pool_id: Nat, deposit_id: Nat, shares: Nat) -> Result<(), String> {
     let caller = ic_cdk::caller();
+    let cover_canister = STATE.with(|state| {
+        let state = state.borrow();
+        ensure_configured(&state)?;
+        state.cover_address.ok_or("Cover canister address not set".to_string())
+    })?;
+    let covers = sold_covers(cover_canister, &pool_id).await?;
 
-    let (amount_to_mint, bq_btc_address) = STATE.with(|state| {
+    let (redemption, bq_btc_address) = STATE.with(|state| {
         let mut state = state.borrow_mut();
         let bq_btc_address = state.bq_btc_address.ok_or("bqBTC canister address not set".to_string())?;
         let pool = state.pools.get_mut(&pool_id).ok_or("Pool not found")?;
@@ -232,65 +373,158 @@ async fn withdraw(pool_id: Nat, amount: Nat) -> Result<(), String> {
             return Err("Pool is inactive".to_string());
         }
 
-        if let Some(caller_deposit) = pool.deposits.get_mut(&caller) {
-            let current_time = Nat::from(ic_cdk::api::time() / 1_000_000_000);
-            if current_time < caller_deposit.expiry_date {
-                return Err("Cant withdraw before the end of a deposit period".to_string());
-            }
-            if caller_deposit.status == Status::Withdrawn {
-                return Err("Caller has already withdrawn".to_string());
-            } 
-            if caller_deposit.amount == Nat::from(0u64) {
-                return Err("Caller deposit is 0".to_string());
-            }
-            if amount > caller_deposit.amount {
-                return Err("Amount is more than caller deposit".to_string());
-            }
+        let now = ic_cdk::api::time() / 1_000_000_000;
+        rewards::accrue(pool, now);
+        let reward_index = pool.reward_index.clone();
+        let amount = shares::to_amount(&shares, &pool.total_shares, &pool.tvl);
+        let mut caller_deposit = find_deposit(caller, &pool_id, &deposit_id).ok_or("Deposit not found for caller".to_string())?;
+        if Nat::from(now) < caller_deposit.expiry_date {
+            return Err("Cant withdraw before the end of a deposit period".to_string());
+        }
+        if caller_deposit.status == Status::Withdrawn {
+            return Err("Caller has already withdrawn".to_string());
+        }
+        if caller_deposit.shares == Nat::from(0u64) {
+            return Err("Caller deposit is 0".to_string());
+        }
+        if shares > caller_deposit.shares {
+            return Err("Shares are more than caller deposit".to_string());
+        }
+        if amount == Nat::from(0u64) {
+            return Err("Shares are worth nothing to withdraw".to_string());
+        }
+        let remaining_tvl = pool.tvl.clone() - amount.clone();
+        if covers.iter().any(|cover| cover.cover_values.clone() * Nat::from(100u64) > remaining_tvl.clone() * cover.capacity.clone()) {
+            return Err("Withdrawal would leave cover already sold unbacked".to_string());
+        }
 
-            caller_deposit.amount -= amount.clone();
-            pool.tvl -= amount.clone();
-            Ok((amount.clone(), bq_btc_address))
-        } else {
-            Err("No deposit found for caller".to_string())
+        rewards::settle(&mut caller_deposit, &reward_index);
+        let basis = caller_deposit.amount.clone() * shares.clone() / caller_deposit.shares.clone();
+        caller_deposit.shares -= shares.clone();
+        caller_deposit.amount -= basis.clone();
+        if caller_deposit.shares == Nat::from(0u64) {
+            caller_deposit.status = Status::Withdrawn;
         }
+        save_deposit(caller_deposit);
+        pool.total_shares -= shares.clone();
+        pool.tvl -= amount.clone();
+        Ok((Redemption { shares, basis, amount }, bq_btc_address))
     })?;
 
-    let mint_result: Result<(), _> = call(bq_btc_address, "mint", (caller, amount_to_mint)).await;
+    let transfer_result: Result<(TxReceipt,), _> = call(bq_btc_address, "transfer", (caller, redemption.amount.clone())).await;
 
-    mint_result.map_err(|err| format!("Error minting BQ BTC: {:?}", err))
+    match transfer_result {
+        Ok((Ok(_),)) => {
+            refresh_cover_capacity(cover_canister, &pool_id).await;
+            Ok(())
+        },
+        Ok((Err(err),)) => {
+            restore_withdrawal(&pool_id, caller, &deposit_id, &redemption);
+            Err(format!("Error transferring BQ BTC: {:?}", err))
+        },
+        Err(err) => {
+            restore_withdrawal(&pool_id, caller, &deposit_id, &redemption);
+            Err(format!("Error transferring BQ BTC: {:?}", err))
+        },
+    }
 }
 
+// Live cover records drawing on a pool. The copies in `pool_covers` only change when a cover is created
+// or resized, so the amounts sold are read from the cover canister.
+async fn sold_covers(cover_canister: Principal, pool_id: &Nat) -> Result<Vec<Cover>, String> {
+    let covers_result: Result<(Result<Vec<Cover>, String>,), _> = call(cover_canister, "getAllAvailableCovers", ()).await;
+    let (covers,) = covers_result.map_err(|_| "Failed to read covers sold against the pool".to_string())?;
+    Ok(covers?.into_iter().filter(|cover| cover.pool_id == *pool_id).collect())
+}
+
+// Recomputes what each cover on the pool may still sell from the pool's current TVL. The TVL change has
+// already happened, so a stale cover is logged rather than failing the caller.
+async fn refresh_cover_capacity(cover_canister: Principal, pool_id: &Nat) {
+    let pool_covers = get_pool_covers(pool_id.clone()).await.unwrap_or_default();
+    for cover in pool_covers.iter() {
+        let update_result: Result<(Result<(), String>,), _> = call(cover_canister, "updateMaxAmount", (cover.id.clone(),)).await;
+        if !matches!(update_result, Ok((Ok(()),))) {
+            ic_cdk::println!("Failed to update cover id {}", cover.id);
+        }
+    }
+}
+
+struct Redemption {
+    shares: Nat,
+    basis: Nat,
+    amount: Nat,
+}
+
+fn restore_withdrawal(pool_id: &Nat, lp: Principal, deposit_id: &Nat, redemption: &Redemption) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(pool) = state.pools.get_mut(pool_id) {
+            rewards::accrue(pool, ic_cdk::api::time() / 1_000_000_000);
+            let reward_index = pool.reward_index.clone();
+            if let Some(mut deposit) = find_deposit(lp, pool_id, deposit_id) {
+                rewards::settle(&mut deposit, &reward_index);
+                deposit.shares += redemption.shares.clone();
+                deposit.amount += redemption.basis.clone();
+                deposit.status = Status::Active;
+                save_deposit(deposit);
+            }
+            pool.total_shares += redemption.shares.clone();
+            pool.tvl += redemption.amount.clone();
+        }
+    });
+}
+
+// Shares `amount` would mint in `pool` right now, or why the pool cannot take it.
+fn shares_for_deposit(pool: &Pool, amount: &Nat) -> Result<Nat, String> {
+    if !pool.is_active {
+        return Err("Pool is inactive".to_string());
+    }
+    let minted = shares::to_shares(amount, &pool.total_shares, &pool.tvl)?;
+    if minted == Nat::from(0u64) {
+        return Err("Deposit is too small to mint any shares".to_string());
+    }
+    Ok(minted)
+}
+
+// Each call opens a new position with its own period and payout; the returned id addresses it afterwards.
 #[update(name = "deposit")]
-async fn deposit(pool_id: Nat, amount: Nat) -> Result<(), String> {
+async fn deposit(pool_id: Nat, amount: Nat) -> Result<Nat, String> {
     let caller = ic_cdk::caller();
 
-    let (bq_btc_address, daily_payout, min_period) = STATE.with(|state| {
+    let (bq_btc_address, cover_canister, daily_payout, min_period) = STATE.with(|state| {
         let state = state.borrow();
+        ensure_configured(&state)?;
         let pool = state.pools.get(&pool_id).ok_or("Pool not found")?;
-        
-        if !pool.is_active {
-            return Err("Pool is inactive".to_string());
-        }
+        shares_for_deposit(pool, &amount)?;
 
         let bq_btc_address = state.bq_btc_address.ok_or("bqBTC canister address not set".to_string())?;
-        let daily_payout = (amount.clone() * pool.apy.clone()) / Nat::from(100u64) / Nat::from(365u64);
+        let cover_canister = state.cover_address.ok_or("Cover canister address not set".to_string())?;
+        let daily_payout = rewards::daily_payout(&amount, &pool.apy);
         let min_period = pool.min_period.clone();
 
-        Ok((bq_btc_address, daily_payout, min_period))
+        Ok((bq_btc_address, cover_canister, daily_payout, min_period))
     })?;
 
-    let burn_result: Result<(), _> = call(bq_btc_address, "burn", (caller, amount.clone())).await;
+    let transfer_result: Result<(TxReceipt,), _> = call(bq_btc_address, "transfer_from", (caller, ic_cdk::id(), amount.clone())).await;
 
-    match burn_result {
-        Ok(_) => {
-            STATE.with(|state| {
+    match transfer_result {
+        Ok((Ok(_),)) => {
+            // The pool may have changed during the transfer, so it is checked again before minting.
+            let deposit_id = STATE.with(|state| {
                 let mut state = state.borrow_mut();
-                let pool = state.pools.get_mut(&pool_id).expect("Pool should be found");
+                let pool = state.pools.get_mut(&pool_id).ok_or("Pool not found".to_string())?;
+                rewards::accrue(pool, ic_cdk::api::time() / 1_000_000_000);
+                let minted = shares_for_deposit(pool, &amount)?;
+                pool.total_shares += minted.clone();
                 pool.tvl += amount.clone();
+                pool.deposit_count += Nat::from(1u64);
+                let deposit_id = pool.deposit_count.clone();
 
                 let deposit = Deposit {
+                    id: deposit_id.clone(),
                     lp: caller,
                     amount: amount.clone(),
+                    shares: minted,
                     pool_id: pool_id.clone(),
                     daily_payout,
                     status: Status::Active,
@@ -298,95 +532,277 @@ async fn deposit(pool_id: Nat, amount: Nat) -> Result<(), String> {
                     start_date: Nat::from(ic_cdk::api::time() / 1_000_000_000),
                     expiry_date: Nat::from(ic_cdk::api::time() / 1_000_000_000) + min_period * Nat::from(86400u64),
                     accrued_payout: Nat::from(0u64),
+                    reward_debt: pool.reward_index.clone(),
                 };
 
-                pool.deposits.insert(caller, deposit);
+                save_deposit(deposit);
 
                 if !state.participants.contains(&caller) {
                     state.participants.push(caller);
                 }
                 *state.participation.entry(caller).or_insert(Nat::from(0u64)) += Nat::from(1u64);
+                Ok::<Nat, String>(deposit_id)
             });
 
-            Ok(())
+            match deposit_id {
+                Ok(deposit_id) => {
+                    refresh_cover_capacity(cover_canister, &pool_id).await;
+                    Ok(deposit_id)
+                },
+                Err(err) => {
+                    let refund: Result<(TxReceipt,), _> = call(bq_btc_address, "transfer", (caller, amount.clone())).await;
+                    if !matches!(refund, Ok((Ok(_),))) {
+                        ic_cdk::println!("Failed to refund a rejected deposit of {} to {}", amount, caller);
+                    }
+                    Err(err)
+                },
+            }
         },
-        Err(err) => Err(format!("Error burning BQ BTC: {:?}", err)),
+        Ok((Err(err),)) => Err(format!("Error transferring BQ BTC: {:?}", err)),
+        Err(err) => Err(format!("Error transferring BQ BTC: {:?}", err)),
     }
 }
 
-#[update(name="claimProposalFunds")] 
-pub async fn claim_proposal_funds(proposal_id: Nat) -> Result<(), String> { 
-    let caller = ic_cdk::caller(); 
-    let (gov_canister, bqbtc_canister, cover_canister) = STATE.with(|state| { 
-        let state = state.borrow(); 
-        let gov_canister = state.gov_address.ok_or("Governance canister address not set")?; 
-        let bqbtc_canister = state.bq_btc_address.ok_or("bqBTC canister address not set")?; 
-        let cover_canister = state.cover_address.ok_or("Cover canister address not set")?; 
-        Ok::<(Principal, Principal, Principal), String>((gov_canister, bqbtc_canister, cover_canister)) 
-    })?; 
- 
-    let proposal_details: Result<(Proposal,), _> = call(gov_canister, "getProposalDetails", (caller, proposal_id.clone())).await; 
-    let (proposal, ) = proposal_details.map_err(|_| "Failed to get proposal details")?; 
-    if proposal.status != ProposalStatus::Approved || proposal.executed { 
-        return Err("Proposal not approved".to_string()); 
-    } 
-    if proposal.proposal_param.user != caller { 
-        return Err("Not a valid proposal".to_string()); 
-    } 
- 
-    let _ = STATE.with(|state | { 
-        let mut state = state.borrow_mut(); 
-        let pool = state.pools.get_mut(&proposal.proposal_param.pool_id).ok_or("Pool should be found")?; 
- 
-        if !pool.is_active { 
-            return Err("Pool is not active".to_string()); 
-        } 
-        if pool.tvl < proposal.proposal_param.claim_amount { 
-            return Err("Not enough funds in the pool".to_string()); 
-        } 
-         
-        pool.tcp += proposal.proposal_param.claim_amount.clone(); 
-        pool.tvl -= proposal.proposal_param.claim_amount.clone(); 
- 
-        Ok::<(), String>(()) 
-    }); 
- 
-    let pool_covers = get_pool_covers(proposal.proposal_param.pool_id).await.map_err(|_| "Error getting pool covers")?; 
-    for cover in pool_covers.iter() {
-        let update_result: Result<((),), _> = call(
-            cover_canister, 
-            "updateMaxAmount", 
-            (caller, cover.id.clone())
-        ).await;
-        update_result.map_err(|_| format!("Failed to update cover id {}", cover.id))?;
-    } 
- 
-    let update_status: Result<((),), _> = call(
-        gov_canister, 
-        "updateProposalStatusToClaimed", 
-        (caller, proposal_id)
-    ).await;
-    update_status.map_err(|_| "Failed to update proposal status")?;
-
-    let mint_result: Result<((),), _> = call(
-        bqbtc_canister, 
-        "mint", 
-        (caller, proposal.proposal_param.claim_amount)
-    ).await;
-    mint_result.map_err(|_| "Error minting BQ BTC")?;
- 
-    Ok(()) 
+#[update(name = "payoutClaim")]
+pub async fn payout_claim(user: Principal, pool_id: Nat, amount: Nat) -> Result<Nat, String> {
+    let caller = ic_cdk::caller();
+    let (bqbtc_canister, cover_canister) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        ensure_configured(&state)?;
+        if state.claims_address != Some(caller) {
+            return Err("Only the claims canister can pay out claims".to_string());
+        }
+        let bqbtc_canister = state.bq_btc_address.ok_or("bqBTC canister address not set")?;
+        let cover_canister = state.cover_address.ok_or("Cover canister address not set")?;
+        let pool = state.pools.get_mut(&pool_id).ok_or("Pool should be found")?;
+
+        if !pool.is_active {
+            return Err("Pool is not active".to_string());
+        }
+        if pool.tvl < amount {
+            return Err("Not enough funds in the pool".to_string());
+        }
+
+        rewards::accrue(pool, ic_cdk::api::time() / 1_000_000_000);
+        pool.tcp += amount.clone();
+        pool.tvl -= amount.clone();
+
+        Ok::<(Principal, Principal), String>((bqbtc_canister, cover_canister))
+    })?;
+
+    let transfer_result: Result<(TxReceipt,), _> = call(bqbtc_canister, "transfer", (user, amount.clone())).await;
+    let block_index = match transfer_result {
+        Ok((Ok(block_index),)) => block_index,
+        failure => {
+            STATE.with(|state| {
+                if let Some(pool) = state.borrow_mut().pools.get_mut(&pool_id) {
+                    rewards::accrue(pool, ic_cdk::api::time() / 1_000_000_000);
+                    pool.tcp -= amount.clone();
+                    pool.tvl += amount.clone();
+                }
+            });
+            return match failure {
+                Ok((Err(err),)) => Err(format!("Error transferring BQ BTC: {:?}", err)),
+                _ => Err("Error transferring BQ BTC".to_string()),
+            };
+        }
+    };
+
+    refresh_cover_capacity(cover_canister, &pool_id).await;
+    Ok(block_index)
+}
+
+#[update(name = "setCanisterIds")]
+fn set_canister_ids(ids: CanisterIds) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) {
+            return Err("Only the owner can set canister ids".to_string());
+        }
+        state.bq_btc_address = ids.bqbtc.or(state.bq_btc_address);
+        state.cover_address = ids.cover.or(state.cover_address);
+        state.gov_address = ids.governance.or(state.gov_address);
+        state.claims_address = ids.claims.or(state.claims_address);
+        Ok(())
+    })
+}
+
+#[query(name = "getCanisterIds")]
+fn get_canister_ids() -> CanisterIds {
+    STATE.with(|state| {
+        let state = state.borrow();
+        CanisterIds {
+            bqbtc: state.bq_btc_address,
+            cover: state.cover_address,
+            governance: state.gov_address,
+            claims: state.claims_address,
+        }
+    })
+}
+
+async fn sync_registry() -> Result<u64, String> {
+    let registry = STATE.with(|state| state.borrow().registry.ok_or("Registry canister not set".to_string()))?;
+    let result: Result<(ProtocolCanisters,), _> = call(registry, "getCanisters", ()).await;
+    let (canisters,) = result.map_err(|_| "Failed to read the registry".to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if canisters.version > state.registry_version {
+            state.bq_btc_address = canisters.bqbtc.or(state.bq_btc_address);
+            state.cover_address = canisters.cover.or(state.cover_address);
+            state.gov_address = canisters.governance.or(state.gov_address);
+            state.claims_address = canisters.claims.or(state.claims_address);
+            state.registry_version = canisters.version;
+        }
+        Ok(state.registry_version)
+    })
+}
+
+fn schedule_registry_sync() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(async {
+        let _ = sync_registry().await;
+    }));
+}
+
+#[update(name = "setRegistry")]
+pub fn set_registry(registry: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) {
+            return Err("Only the owner can set the registry".to_string());
+        }
+        state.registry = Some(registry);
+        state.registry_version = 0;
+        Ok(())
+    })?;
+    schedule_registry_sync();
+    Ok(())
+}
+
+#[update(name = "syncRegistry")]
+pub async fn sync_registry_now() -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if STATE.with(|state| state.borrow().owner != Some(caller)) {
+        return Err("Only the owner can sync the registry".to_string());
+    }
+    sync_registry().await
+}
+
+#[query(name = "getRegistry")]
+pub fn get_registry() -> (Option<Principal>, u64) {
+    STATE.with(|state| {
+        let state = state.borrow();
+        (state.registry, state.registry_version)
+    })
 }
 
 #[query(name = "getUserDeposit")]
-pub async fn get_user_deposit(pool_id: Nat, user: Principal) -> Result<Deposit, String> {
+pub async fn get_user_deposit(pool_id: Nat, user: Principal, deposit_id: Nat) -> Result<Deposit, String> {
+    settled_deposit(&pool_id, user, &deposit_id)
+}
+
+#[query(name = "getUserDeposits")]
+pub async fn get_user_deposits(pool_id: Nat, user: Principal) -> Result<Vec<Deposit>, String> {
     STATE.with(| state | {
         let state = state.borrow();
         let pool = state.pools.get(&pool_id).ok_or("Pool should be found")?;
-        
-        let user_deposit = pool.deposits.get(&user).ok_or("User deposit not found")?;
 
-        Ok(user_deposit.clone())
+        let reward_index = rewards::index_at(pool, ic_cdk::api::time() / 1_000_000_000);
+        let mut deposits: Vec<Deposit> = deposits_of(user).into_iter().filter(|deposit| deposit.pool_id == pool_id).collect();
+        for deposit in deposits.iter_mut() {
+            rewards::settle(deposit, &reward_index);
+        }
+
+        Ok(deposits)
+    })
+}
+
+#[query(name = "getClaimableRewards")]
+pub fn get_claimable_rewards(pool_id: Nat, user: Principal, deposit_id: Nat) -> Result<Nat, String> {
+    settled_deposit(&pool_id, user, &deposit_id).map(|deposit| deposit.accrued_payout)
+}
+
+// A copy of the position with rewards accrued up to now, as queries cannot advance the pool's index.
+fn settled_deposit(pool_id: &Nat, user: Principal, deposit_id: &Nat) -> Result<Deposit, String> {
+    STATE.with(| state | {
+        let state = state.borrow();
+        let pool = state.pools.get(pool_id).ok_or("Pool should be found")?;
+        let mut deposit = find_deposit(user, pool_id, deposit_id).ok_or("User deposit not found")?;
+        rewards::settle(&mut deposit, &rewards::index_at(pool, ic_cdk::api::time() / 1_000_000_000));
+        Ok(deposit)
+    })
+}
+
+// Pays a position's accrued rewards out of the premiums held by the pool. Rewards are zeroed before the
+// transfer and credited back if it fails.
+#[update(name = "payRewards")]
+pub async fn pay_rewards(lp: Principal, pool_id: Nat, deposit_id: Nat) -> Result<Nat, String> {
+    let caller = ic_cdk::caller();
+    let (amount, bq_btc_address) = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.cover_address != Some(caller) {
+            return Err("Only the cover canister can pay rewards".to_string());
+        }
+        let bq_btc_address = state.bq_btc_address.ok_or("bqBTC canister address not set".to_string())?;
+        let pool = state.pools.get_mut(&pool_id).ok_or("Pool not found")?;
+        rewards::accrue(pool, ic_cdk::api::time() / 1_000_000_000);
+        let reward_index = pool.reward_index.clone();
+        let apy = pool.apy.clone();
+        let mut deposit = find_deposit(lp, &pool_id, &deposit_id).ok_or("User deposit not found")?;
+        let value = shares::to_amount(&deposit.shares, &pool.total_shares, &pool.tvl);
+
+        rewards::settle(&mut deposit, &reward_index);
+        deposit.daily_payout = rewards::daily_payout(&value, &apy);
+        if deposit.accrued_payout == Nat::from(0u64) {
+            return Err("No claimable reward".to_string());
+        }
+        let amount = std::mem::replace(&mut deposit.accrued_payout, Nat::from(0u64));
+        save_deposit(deposit);
+        Ok::<(Nat, Principal), String>((amount, bq_btc_address))
+    })?;
+
+    let transfer_result: Result<(TxReceipt,), _> = call(bq_btc_address, "transfer", (lp, amount.clone())).await;
+    match transfer_result {
+        Ok((Ok(_),)) => Ok(amount),
+        failure => {
+            if let Some(mut deposit) = find_deposit(lp, &pool_id, &deposit_id) {
+                deposit.accrued_payout += amount.clone();
+                save_deposit(deposit);
+            }
+            match failure {
+                Ok((Err(err),)) => Err(format!("Error transferring BQ BTC: {:?}", err)),
+                _ => Err("Error transferring BQ BTC".to_string()),
+            }
+        },
+    }
+}
+
+// Books premium income the cover canister has already transferred to this canister for the pool's LPs.
+#[update(name = "depositPremium")]
+pub fn deposit_premium(pool_id: Nat, amount: Nat) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.cover_address != Some(caller) {
+            return Err("Only the cover canister can deposit premiums".to_string());
+        }
+        let pool = state.pools.get_mut(&pool_id).ok_or("Pool not found")?;
+        rewards::accrue(pool, ic_cdk::api::time() / 1_000_000_000);
+        pool.premium_reserve += amount;
+        Ok(())
+    })
+}
+
+#[query(name = "getDepositValue")]
+pub fn get_deposit_value(pool_id: Nat, user: Principal, deposit_id: Nat) -> Result<Nat, String> {
+    STATE.with(| state | {
+        let state = state.borrow();
+        let pool = state.pools.get(&pool_id).ok_or("Pool should be found")?;
+        let deposit = find_deposit(user, &pool_id, &deposit_id).ok_or("User deposit not found")?;
+
+        Ok(shares::to_amount(&deposit.shares, &pool.total_shares, &pool.tvl))
     })
 }
 
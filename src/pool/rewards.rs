@@ -0,0 +1,143 @@
+use candid::Nat;
+
+use crate::types::{Deposit, Pool};
+
+// Reward per share is kept with 18 decimals of precision so small pools still accrue between events.
+const INDEX_SCALE: u64 = 1_000_000_000_000_000_000;
+const SECONDS_PER_YEAR: u64 = 365 * 86_400;
+
+// Rewards released to LPs between the last accrual and `now`. They stream at the pool's APY but
+// never exceed the premiums the pool has received, so every reward is backed by underwriting income.
+fn released(pool: &Pool, now: &Nat) -> Nat {
+    let zero = Nat::from(0u64);
+    if *now <= pool.last_accrued_at || !pool.is_active || pool.total_shares == zero {
+        return zero;
+    }
+
+    let elapsed = now.clone() - pool.last_accrued_at.clone();
+    let reward = pool.tvl.clone() * pool.apy.clone() * elapsed / Nat::from(100u64) / Nat::from(SECONDS_PER_YEAR);
+    reward.min(pool.premium_reserve.clone())
+}
+
+// What the pool's reward-per-share index reads at `now`.
+pub fn index_at(pool: &Pool, now: u64) -> Nat {
+    let reward = released(pool, &Nat::from(now));
+    if reward == Nat::from(0u64) {
+        return pool.reward_index.clone();
+    }
+    pool.reward_index.clone() + reward * Nat::from(INDEX_SCALE) / pool.total_shares.clone()
+}
+
+// Advances the index to `now`. Must run before anything that changes the pool's TVL, shares, APY, status or premiums.
+pub fn accrue(pool: &mut Pool, now: u64) {
+    let now = Nat::from(now);
+    let reward = released(pool, &now);
+    if reward > Nat::from(0u64) {
+        pool.reward_index += reward.clone() * Nat::from(INDEX_SCALE) / pool.total_shares.clone();
+        pool.premium_reserve -= reward;
+    }
+    if now > pool.last_accrued_at {
+        pool.last_accrued_at = now;
+    }
+}
+
+fn earned(deposit: &Deposit, reward_index: &Nat) -> Nat {
+    deposit.shares.clone() * (reward_index.clone() - deposit.reward_debt.clone()) / Nat::from(INDEX_SCALE)
+}
+
+// Moves everything a position has earned since it was last settled into `accrued_payout`.
+pub fn settle(deposit: &mut Deposit, reward_index: &Nat) {
+    deposit.accrued_payout += earned(deposit, reward_index);
+    deposit.reward_debt = reward_index.clone();
+}
+
+pub fn pending(deposit: &Deposit, reward_index: &Nat) -> Nat {
+    deposit.accrued_payout.clone() + earned(deposit, reward_index)
+}
+
+pub fn daily_payout(value: &Nat, apy: &Nat) -> Nat {
+    value.clone() * apy.clone() / Nat::from(100u64) / Nat::from(365u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{deposit, nat, pool, TOTAL_SHARES};
+
+    fn funded_pool(premium_reserve: u64) -> Pool {
+        Pool { premium_reserve: nat(premium_reserve), ..pool() }
+    }
+
+    #[test]
+    fn accrue_streams_rewards_at_the_pool_apy() {
+        let mut pool = funded_pool(1_000_000);
+        accrue(&mut pool, SECONDS_PER_YEAR);
+
+        // 10% of a 1,000,000 TVL over a year, spread across 1,000,000 shares.
+        assert_eq!(pool.reward_index, nat(100_000) * nat(INDEX_SCALE) / nat(TOTAL_SHARES));
+        assert_eq!(pool.premium_reserve, nat(900_000));
+        assert_eq!(pool.last_accrued_at, nat(SECONDS_PER_YEAR));
+    }
+
+    #[test]
+    fn accrue_never_releases_more_than_the_premium_reserve() {
+        let mut pool = funded_pool(40_000);
+        accrue(&mut pool, SECONDS_PER_YEAR);
+
+        assert_eq!(pool.reward_index, nat(40_000) * nat(INDEX_SCALE) / nat(TOTAL_SHARES));
+        assert_eq!(pool.premium_reserve, nat(0));
+    }
+
+    #[test]
+    fn inactive_pools_stop_accruing_but_advance_the_clock() {
+        let mut pool = funded_pool(1_000_000);
+        pool.is_active = false;
+        accrue(&mut pool, SECONDS_PER_YEAR);
+
+        assert_eq!(pool.reward_index, nat(0));
+        assert_eq!(pool.premium_reserve, nat(1_000_000));
+        assert_eq!(pool.last_accrued_at, nat(SECONDS_PER_YEAR));
+    }
+
+    #[test]
+    fn index_at_reads_ahead_without_accruing() {
+        let pool = funded_pool(1_000_000);
+        let mut accrued = pool.clone();
+        accrue(&mut accrued, SECONDS_PER_YEAR / 2);
+
+        assert_eq!(index_at(&pool, SECONDS_PER_YEAR / 2), accrued.reward_index);
+        assert_eq!(pool.reward_index, nat(0));
+        assert_eq!(index_at(&pool, 0), nat(0));
+    }
+
+    #[test]
+    fn settle_pays_each_share_of_the_index_once() {
+        let mut pool = funded_pool(1_000_000);
+        accrue(&mut pool, SECONDS_PER_YEAR);
+        let mut position = deposit(500_000, nat(0));
+
+        assert_eq!(pending(&position, &pool.reward_index), nat(50_000));
+        settle(&mut position, &pool.reward_index);
+        assert_eq!(position.accrued_payout, nat(50_000));
+        assert_eq!(position.reward_debt, pool.reward_index);
+
+        settle(&mut position, &pool.reward_index);
+        assert_eq!(position.accrued_payout, nat(50_000));
+        assert_eq!(pending(&position, &pool.reward_index), nat(50_000));
+    }
+
+    #[test]
+    fn late_deposits_do_not_earn_past_rewards() {
+        let mut pool = funded_pool(1_000_000);
+        accrue(&mut pool, SECONDS_PER_YEAR);
+        let position = deposit(500_000, pool.reward_index.clone());
+
+        assert_eq!(pending(&position, &pool.reward_index), nat(0));
+    }
+
+    #[test]
+    fn daily_payout_spreads_the_apy_over_the_year() {
+        assert_eq!(daily_payout(&nat(365_000), &nat(10)), nat(100));
+        assert_eq!(daily_payout(&nat(0), &nat(10)), nat(0));
+    }
+}
@@ -0,0 +1,66 @@
+use candid::Nat;
+
+// Shares minted for `amount`. The first deposit into an empty pool mints shares one to one with bqBTC.
+// A pool whose TVL was paid out entirely still has shares outstanding, and minting next to them would
+// hand part of the new deposit to their holders, so it takes no deposits.
+pub fn to_shares(amount: &Nat, total_shares: &Nat, tvl: &Nat) -> Result<Nat, String> {
+    let zero = Nat::from(0u64);
+    if *total_shares == zero {
+        Ok(amount.clone())
+    } else if *tvl == zero {
+        Err("Pool has been depleted by claims".to_string())
+    } else {
+        Ok(amount.clone() * total_shares.clone() / tvl.clone())
+    }
+}
+
+// bqBTC redeemable for `shares`, so claim payouts and premium income move every LP's position pro rata.
+pub fn to_amount(shares: &Nat, total_shares: &Nat, tvl: &Nat) -> Nat {
+    if *total_shares == Nat::from(0u64) {
+        Nat::from(0u64)
+    } else {
+        shares.clone() * tvl.clone() / total_shares.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::nat;
+
+    #[test]
+    fn first_deposit_mints_one_to_one() {
+        assert_eq!(to_shares(&nat(1_000), &nat(0), &nat(0)), Ok(nat(1_000)));
+    }
+
+    #[test]
+    fn depleted_pool_with_outstanding_shares_takes_no_deposits() {
+        assert!(to_shares(&nat(1_000), &nat(500), &nat(0)).is_err());
+    }
+
+    #[test]
+    fn later_deposits_mint_at_the_current_share_price() {
+        assert_eq!(to_shares(&nat(50), &nat(100), &nat(200)), Ok(nat(25)));
+        assert_eq!(to_amount(&nat(25), &nat(125), &nat(250)), nat(50));
+    }
+
+    #[test]
+    fn redemptions_follow_tvl_changes_pro_rata() {
+        // A claim payout halves the TVL, so every position is worth half as much.
+        assert_eq!(to_amount(&nat(40), &nat(100), &nat(500)), nat(200));
+        // Premium income raises it for everyone.
+        assert_eq!(to_amount(&nat(40), &nat(100), &nat(1_500)), nat(600));
+    }
+
+    #[test]
+    fn rounding_never_redeems_more_than_was_deposited() {
+        let shares = to_shares(&nat(7), &nat(3), &nat(10)).unwrap();
+        assert_eq!(shares, nat(2));
+        assert!(to_amount(&shares, &nat(3), &nat(10)) <= nat(7));
+    }
+
+    #[test]
+    fn shares_of_an_empty_pool_are_worth_nothing() {
+        assert_eq!(to_amount(&nat(10), &nat(0), &nat(100)), nat(0));
+    }
+}
@@ -0,0 +1,46 @@
+// Fixtures shared by the unit tests of the pool's share and reward math.
+use candid::{Nat, Principal};
+
+use crate::types::{Deposit, Pool, RiskType, Status};
+
+pub const TOTAL_SHARES: u64 = 1_000_000;
+
+pub fn nat(value: u64) -> Nat {
+    Nat::from(value)
+}
+
+// An active pool at a 10% APY whose shares are still worth one bqBTC each.
+pub fn pool() -> Pool {
+    Pool {
+        pool_name: "Pool".to_string(),
+        risk_type: RiskType::Slashing,
+        apy: nat(10),
+        min_period: nat(30),
+        tvl: nat(TOTAL_SHARES),
+        tcp: nat(0),
+        is_active: true,
+        percentage_split_balance: nat(100),
+        deposit_count: nat(1),
+        total_shares: nat(TOTAL_SHARES),
+        reward_index: nat(0),
+        last_accrued_at: nat(0),
+        premium_reserve: nat(0),
+    }
+}
+
+pub fn deposit(shares: u64, reward_debt: Nat) -> Deposit {
+    Deposit {
+        id: nat(1),
+        lp: Principal::anonymous(),
+        amount: nat(shares),
+        shares: nat(shares),
+        pool_id: nat(1),
+        daily_payout: nat(0),
+        status: Status::Active,
+        days_left: nat(0),
+        start_date: nat(0),
+        expiry_date: nat(0),
+        accrued_payout: nat(0),
+        reward_debt,
+    }
+}
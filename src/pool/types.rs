@@ -1,6 +1,5 @@
-use std::collections::HashMap;
-
 use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
 
 #[derive(Clone, CandidType, Deserialize)]
 pub struct Cover {
@@ -27,7 +26,17 @@ pub struct GenericCoverInfo {
     pub claim_paid: Nat,
     pub cover_period: Nat,
     pub end_day: Nat,
-    pub is_active: bool
+    pub is_active: bool,
+    pub premium_rate_bps: Nat,
+    pub premium_paid: Nat
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct PricingCurve {
+    pub base_rate_bps: Nat,
+    pub kink_utilization_bps: Nat,
+    pub slope_bps: Nat,
+    pub jump_slope_bps: Nat
 }
 
 #[derive(Clone, CandidType, Deserialize, PartialEq)]
@@ -42,7 +51,87 @@ pub struct Proposal {
     pub executed: bool,
     pub proposal_param: ProposalParam,
     pub voters_for: Vec<Principal>,
-    pub voters_against: Vec<Principal>
+    pub voters_against: Vec<Principal>,
+    pub status_history: Vec<StatusChange>,
+    pub snapshot_at: Nat,
+    pub quorum: Nat,
+    pub approval_threshold_bps: Nat,
+    pub evidence: Option<ClaimEvidence>,
+    pub kind: ProposalKind,
+    pub execution_error: Option<String>
+}
+
+#[derive(Clone, CandidType, Deserialize, PartialEq)]
+pub enum ProposalKind {
+    Claim,
+    Action(GovernanceAction)
+}
+
+#[derive(Clone, CandidType, Deserialize, PartialEq)]
+pub enum GovernanceAction {
+    UpdatePool { pool_id: Nat, apy: Nat, min_period: Nat },
+    DeactivatePool { pool_id: Nat },
+    SetVotingDuration { seconds: u64 },
+    UpdateRewardAmount { amount: Nat },
+    AddAdmin { admin: Principal },
+    SetTimelockDelay { seconds: u64 },
+    GrantRole { role: Role, principal: Principal },
+    RevokeRole { role: Role, principal: Principal },
+    SetCanisterIds {
+        governance_token: Option<Principal>,
+        bqbtc: Option<Principal>,
+        cover: Option<Principal>,
+        pool: Option<Principal>
+    },
+    SetRegistry { registry: Principal },
+    SetBitcoinCanister { canister: Principal, network: BitcoinNetwork },
+    SetVotingRules { rules: VotingRules },
+    ReleaseClaimPayout { proposal_id: Nat }
+}
+
+#[derive(Clone, CandidType, Deserialize, PartialEq)]
+pub enum QueueStatus {
+    Queued,
+    Executed,
+    Cancelled,
+    Failed
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct QueuedAction {
+    pub id: Nat,
+    pub action: GovernanceAction,
+    pub proposal_id: Option<Nat>,
+    pub queued_by: Principal,
+    pub queued_at: Nat,
+    pub eta: Nat,
+    pub status: QueueStatus,
+    pub resolved_at: Option<Nat>,
+    pub error: Option<String>
+}
+
+#[derive(Clone, CandidType, Deserialize, PartialEq)]
+pub struct ClaimEvidence {
+    pub tx_hash: String,
+    pub btc_address: String,
+    pub amount: u64,
+    pub block_height: u32,
+    pub confirmations: u32,
+    pub verified_at: Nat
+}
+
+#[derive(Clone, CandidType, Deserialize, Default, PartialEq)]
+pub struct VotingRules {
+    pub quorum_bps: u64,
+    pub approval_threshold_bps: u64,
+    pub large_claim_threshold_bps: u64,
+    pub large_claim_tvl_bps: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize, PartialEq)]
+pub struct StatusChange {
+    pub status: ProposalStatus,
+    pub timestamp: Nat
 }
 
 #[derive(Clone, CandidType, Deserialize, PartialEq)]
@@ -51,6 +140,7 @@ pub struct ProposalParam {
     pub risk_type: RiskType,
     pub cover_id: Nat,
     pub tx_hash: String,
+    pub btc_address: String,
     pub description: String,
     pub pool_id: Nat,
     pub claim_amount: Nat
@@ -64,13 +154,17 @@ pub enum RiskType {
     Protocol
 }
 
-#[derive(Clone, CandidType, Deserialize, PartialEq)]
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
 pub enum ProposalStatus {
     Submitted, 
     Pending,
     Approved,
     Claimed,
-    Rejected
+    Rejected,
+    Expired,
+    Cancelled,
+    QuorumNotMet,
+    Executed
 }
 
 #[derive(Clone, CandidType, Deserialize)]
@@ -78,6 +172,100 @@ pub struct Voter {
     pub voted: bool,
     pub vote: bool,
     pub weight: Nat,
+    pub delegated_to: Option<Principal>,
+    pub delegators: Vec<Principal>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Delegation {
+    pub delegate: Principal,
+    pub risk_type: Option<RiskType>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct StakeCheckpoint {
+    pub timestamp: Nat,
+    pub amount: Nat,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct StakeInfo {
+    pub amount: Nat,
+    pub locked_until: Nat,
+}
+
+#[derive(Clone, CandidType, Deserialize, PartialEq)]
+pub enum ClaimStatus {
+    Open,
+    Approved,
+    Paying,
+    Paid,
+    Rejected,
+    Cancelled
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Payout {
+    pub amount: Nat,
+    pub block_index: Nat,
+    pub timestamp: Nat
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Claim {
+    pub id: Nat,
+    pub user: Principal,
+    pub cover_id: Nat,
+    pub pool_id: Nat,
+    pub risk_type: RiskType,
+    pub amount: Nat,
+    pub tx_hash: String,
+    pub btc_address: String,
+    pub description: String,
+    pub proposal_id: Nat,
+    pub evidence: Option<ClaimEvidence>,
+    pub status: ClaimStatus,
+    pub payouts: Vec<Payout>,
+    pub cover_settled: bool,
+    pub created_at: Nat
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq, Eq, Hash)]
+pub enum Role {
+    Admin,
+    Guardian,
+    ClaimsExecutor
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct RoleChange {
+    pub role: Role,
+    pub principal: Principal,
+    pub granted: bool,
+    pub changed_by: Principal,
+    pub timestamp: Nat
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq, Eq)]
+pub enum ProtocolCanister {
+    Pool,
+    Cover,
+    Governance,
+    Bqbtc,
+    Bqtoken,
+    Claims
+}
+
+#[derive(Clone, CandidType, Deserialize, Default)]
+pub struct ProtocolCanisters {
+    pub version: u64,
+    pub pool: Option<Principal>,
+    pub cover: Option<Principal>,
+    pub governance: Option<Principal>,
+    pub bqbtc: Option<Principal>,
+    pub bqtoken: Option<Principal>,
+    pub claims: Option<Principal>,
+    pub updated_at: Nat
 }
 
 #[derive(Clone, CandidType, Deserialize)]
@@ -90,13 +278,19 @@ pub struct Pool {
     pub tcp: Nat,
     pub is_active: bool,
     pub percentage_split_balance: Nat,
-    pub deposits: HashMap<Principal, Deposit>,
+    pub deposit_count: Nat,
+    pub total_shares: Nat,
+    pub reward_index: Nat,
+    pub last_accrued_at: Nat,
+    pub premium_reserve: Nat,
 }
 
 #[derive(Clone, CandidType, Deserialize)]
 pub struct Deposit {
+    pub id: Nat,
     pub lp: Principal,
     pub amount: Nat,
+    pub shares: Nat,
     pub pool_id: Nat,
     pub daily_payout: Nat,
     pub status: Status,
@@ -104,6 +298,7 @@ pub struct Deposit {
     pub start_date: Nat,
     pub expiry_date: Nat,
     pub accrued_payout: Nat,
+    pub reward_debt: Nat,
 }
 
 #[derive(Clone, CandidType, Deserialize, PartialEq)]
@@ -116,12 +311,30 @@ pub enum Status {
 pub struct PoolInfo {
     pub pool_name: String,
     pub pool_id: Nat,
+    pub deposit_id: Nat,
     pub daily_payout: Nat,
     pub deposit_amount: Nat,
+    pub shares: Nat,
+    pub deposit_value: Nat,
     pub apy: Nat,
     pub min_period: Nat,
     pub tvl: Nat,
     pub tcp: Nat,
     pub is_active: bool,
     pub accrued_payout: Nat,
-}
\ No newline at end of file
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub enum TxError {
+    InsufficientBalance,
+    InsufficientAllowance,
+    Unauthorized,
+    LedgerTrap,
+    AmountTooSmall,
+    BlockUsed,
+    ErrorOperationStyle,
+    ErrorTo,
+    Other,
+}
+
+pub type TxReceipt = Result<Nat, TxError>;
@@ -0,0 +1,117 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk_macros::*;
+use shared::stable;
+use std::cell::RefCell;
+
+mod types;
+use types::{ProtocolCanister, ProtocolCanisters};
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::default();
+}
+
+#[derive(CandidType, Deserialize, Default)]
+struct State {
+    owner: Option<Principal>,
+    current: ProtocolCanisters,
+    history: Vec<ProtocolCanisters>,
+}
+
+// Layouts the state has been saved in across upgrades; post_upgrade migrates older ones forward.
+#[derive(CandidType, Deserialize)]
+enum StableState {
+    V1(State),
+}
+
+#[init]
+fn init(owner: Principal) {
+    STATE.with(|state| state.borrow_mut().owner = Some(owner));
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    STATE.with(|state| stable::save_state(&StableState::V1(std::mem::take(&mut *state.borrow_mut()))));
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let saved = match stable::restore_state() {
+        Some(StableState::V1(saved)) => saved,
+        None => {
+            ic_cdk::println!("No saved state found, starting from an empty state");
+            State::default()
+        },
+    };
+    STATE.with(|state| *state.borrow_mut() = saved);
+}
+
+// Publishes a new version of the protocol canister set. Entries not listed keep their current principal,
+// so a single redeployed canister can be swapped without restating the others.
+#[update(name = "setCanisters")]
+pub fn set_canisters(entries: Vec<(ProtocolCanister, Principal)>) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if entries.is_empty() {
+        return Err("No canisters to register".to_string());
+    }
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) {
+            return Err("Only the owner can update the registry".to_string());
+        }
+
+        let mut next = state.current.clone();
+        for (canister, principal) in entries {
+            let slot = match canister {
+                ProtocolCanister::Pool => &mut next.pool,
+                ProtocolCanister::Cover => &mut next.cover,
+                ProtocolCanister::Governance => &mut next.governance,
+                ProtocolCanister::Bqbtc => &mut next.bqbtc,
+                ProtocolCanister::Bqtoken => &mut next.bqtoken,
+                ProtocolCanister::Claims => &mut next.claims,
+            };
+            *slot = Some(principal);
+        }
+        next.version = state.current.version + 1;
+        next.updated_at = Nat::from(ic_cdk::api::time() / 1_000_000_000);
+
+        state.history.push(next.clone());
+        state.current = next;
+        Ok(state.current.version)
+    })
+}
+
+#[query(name = "getCanisters")]
+pub fn get_canisters() -> ProtocolCanisters {
+    STATE.with(|state| state.borrow().current.clone())
+}
+
+#[query(name = "getCanistersAt")]
+pub fn get_canisters_at(version: u64) -> Option<ProtocolCanisters> {
+    STATE.with(|state| state.borrow().history.iter().find(|entry| entry.version == version).cloned())
+}
+
+#[query(name = "getVersion")]
+pub fn get_version() -> u64 {
+    STATE.with(|state| state.borrow().current.version)
+}
+
+#[update(name = "transferOwnership")]
+pub fn transfer_ownership(new_owner: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.owner != Some(caller) {
+            return Err("Only the owner can transfer ownership".to_string());
+        }
+        state.owner = Some(new_owner);
+        Ok(())
+    })
+}
+
+#[query(name = "getOwner")]
+fn get_owner() -> Option<Principal> {
+    STATE.with(|state| state.borrow().owner)
+}
+
+ic_cdk::export_candid!();
@@ -0,0 +1,367 @@
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
+use sha2::{Digest, Sha256};
+
+pub type Subaccount = [u8; 32];
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum Operation {
+    Mint { to: Account, amount: Nat },
+    Burn { from: Account, amount: Nat },
+    Transfer { from: Account, to: Account, amount: Nat, spender: Option<Account> },
+    Approve { from: Account, spender: Account, amount: Nat, expected_allowance: Option<Nat>, expires_at: Option<u64> }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Transaction {
+    pub operation: Operation,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub timestamp: u64
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Block {
+    pub transaction: Transaction,
+    pub effective_fee: Option<Nat>,
+    pub parent_hash: Option<[u8; 32]>
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TransactionWithId {
+    pub id: Nat,
+    pub transaction: Transaction
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct GetTransactionsResponse {
+    pub log_length: Nat,
+    pub transactions: Vec<TransactionWithId>
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>)
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksCallback
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct GetArchivesArgs {
+    pub from: Option<Principal>
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: Nat,
+    pub end: Nat
+}
+
+fn account_value(account: &Account) -> Value {
+    let mut parts = vec![Value::Blob(account.owner.as_slice().to_vec())];
+    if let Some(subaccount) = account.subaccount {
+        parts.push(Value::Blob(subaccount.to_vec()));
+    }
+    Value::Array(parts)
+}
+
+fn field(name: &str, value: Value) -> (String, Value) {
+    (name.to_string(), value)
+}
+
+// Encodes a block using the generic ICRC-3 schema for ICRC-1 and ICRC-2 block types.
+pub fn block_value(block: &Block) -> Value {
+    let transaction = &block.transaction;
+    let mut tx = vec![];
+
+    let block_type = match &transaction.operation {
+        Operation::Mint { to, amount } => {
+            tx.push(field("to", account_value(to)));
+            tx.push(field("amt", Value::Nat(amount.clone())));
+            "1mint"
+        },
+        Operation::Burn { from, amount } => {
+            tx.push(field("from", account_value(from)));
+            tx.push(field("amt", Value::Nat(amount.clone())));
+            "1burn"
+        },
+        Operation::Transfer { from, to, amount, spender } => {
+            tx.push(field("from", account_value(from)));
+            tx.push(field("to", account_value(to)));
+            tx.push(field("amt", Value::Nat(amount.clone())));
+            match spender {
+                Some(spender) => {
+                    tx.push(field("spender", account_value(spender)));
+                    "2xfer"
+                },
+                None => "1xfer",
+            }
+        },
+        Operation::Approve { from, spender, amount, expected_allowance, expires_at } => {
+            tx.push(field("from", account_value(from)));
+            tx.push(field("spender", account_value(spender)));
+            tx.push(field("amt", Value::Nat(amount.clone())));
+            if let Some(expected_allowance) = expected_allowance {
+                tx.push(field("expected_allowance", Value::Nat(expected_allowance.clone())));
+            }
+            if let Some(expires_at) = expires_at {
+                tx.push(field("expires_at", Value::Nat(Nat::from(*expires_at))));
+            }
+            "2approve"
+        },
+    };
+
+    if let Some(fee) = &transaction.fee {
+        tx.push(field("fee", Value::Nat(fee.clone())));
+    }
+    if let Some(memo) = &transaction.memo {
+        tx.push(field("memo", Value::Blob(memo.clone())));
+    }
+    if let Some(created_at_time) = transaction.created_at_time {
+        tx.push(field("ts", Value::Nat(Nat::from(created_at_time))));
+    }
+
+    let mut fields = vec![
+        field("btype", Value::Text(block_type.to_string())),
+        field("ts", Value::Nat(Nat::from(transaction.timestamp))),
+        field("tx", Value::Map(tx)),
+    ];
+    if let Some(fee) = &block.effective_fee {
+        fields.push(field("fee", Value::Nat(fee.clone())));
+    }
+    if let Some(parent_hash) = &block.parent_hash {
+        fields.push(field("phash", Value::Blob(parent_hash.to_vec())));
+    }
+
+    Value::Map(fields)
+}
+
+// Representation-independent hash as specified by ICRC-3.
+pub fn hash_value(value: &Value) -> [u8; 32] {
+    match value {
+        Value::Blob(bytes) => Sha256::digest(bytes).into(),
+        Value::Text(text) => Sha256::digest(text.as_bytes()).into(),
+        Value::Nat(nat) => {
+            let mut buf = vec![];
+            nat.encode(&mut buf).expect("Failed to LEB128-encode nat");
+            Sha256::digest(&buf).into()
+        },
+        Value::Int(int) => {
+            let mut buf = vec![];
+            int.encode(&mut buf).expect("Failed to SLEB128-encode int");
+            Sha256::digest(&buf).into()
+        },
+        Value::Array(values) => {
+            let mut hasher = Sha256::new();
+            for value in values {
+                hasher.update(hash_value(value));
+            }
+            hasher.finalize().into()
+        },
+        Value::Map(entries) => {
+            let mut pairs: Vec<Vec<u8>> = entries
+                .iter()
+                .map(|(key, value)| {
+                    let mut pair = Sha256::digest(key.as_bytes()).to_vec();
+                    pair.extend_from_slice(&hash_value(value));
+                    pair
+                })
+                .collect();
+            pairs.sort();
+
+            let mut hasher = Sha256::new();
+            for pair in pairs {
+                hasher.update(pair);
+            }
+            hasher.finalize().into()
+        },
+    }
+}
+
+const LAST_BLOCK_HASH: &[u8] = b"last_block_hash";
+const LAST_BLOCK_INDEX: &[u8] = b"last_block_index";
+
+fn leb128(index: u64) -> Vec<u8> {
+    let mut buf = vec![];
+    Nat::from(index).encode(&mut buf).expect("Failed to LEB128-encode nat");
+    buf
+}
+
+fn tree_hash(domain: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain.as_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn labeled_leaf_hash(label: &[u8], leaf: &[u8]) -> [u8; 32] {
+    let leaf = tree_hash("ic-hashtree-leaf", &[leaf]);
+    tree_hash("ic-hashtree-labeled", &[label, &leaf])
+}
+
+// Root hash of the tip tree, to be passed to `set_certified_data` whenever a block is appended.
+pub fn tip_certified_data(last_block_index: u64, last_block_hash: &[u8; 32]) -> [u8; 32] {
+    let hash = labeled_leaf_hash(LAST_BLOCK_HASH, last_block_hash);
+    let index = labeled_leaf_hash(LAST_BLOCK_INDEX, &leb128(last_block_index));
+    tree_hash("ic-hashtree-fork", &[&hash, &index])
+}
+
+fn cbor_head(buf: &mut Vec<u8>, major: u8, len: usize) {
+    let major = major << 5;
+    match len {
+        0..=23 => buf.push(major | len as u8),
+        24..=0xff => buf.extend_from_slice(&[major | 24, len as u8]),
+        _ => {
+            buf.push(major | 25);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+    }
+}
+
+fn cbor_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    cbor_head(buf, 2, bytes.len());
+    buf.extend_from_slice(bytes);
+}
+
+fn cbor_labeled_leaf(buf: &mut Vec<u8>, label: &[u8], leaf: &[u8]) {
+    cbor_head(buf, 4, 3);
+    cbor_head(buf, 0, 2);
+    cbor_bytes(buf, label);
+    cbor_head(buf, 4, 2);
+    cbor_head(buf, 0, 3);
+    cbor_bytes(buf, leaf);
+}
+
+// CBOR-encoded hash tree whose root is `tip_certified_data`, returned alongside the certificate.
+pub fn tip_hash_tree(last_block_index: u64, last_block_hash: &[u8; 32]) -> Vec<u8> {
+    let mut buf = vec![0xd9, 0xd9, 0xf7];
+    cbor_head(&mut buf, 4, 3);
+    cbor_head(&mut buf, 0, 1);
+    cbor_labeled_leaf(&mut buf, LAST_BLOCK_HASH, last_block_hash);
+    cbor_labeled_leaf(&mut buf, LAST_BLOCK_INDEX, &leb128(last_block_index));
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn transfer_block(parent_hash: Option<[u8; 32]>) -> Block {
+        let from = Account { owner: Principal::anonymous(), subaccount: None };
+        let to = Account { owner: Principal::management_canister(), subaccount: Some([1; 32]) };
+        Block {
+            transaction: Transaction {
+                operation: Operation::Transfer { from, to, amount: Nat::from(100u64), spender: None },
+                fee: None,
+                memo: None,
+                created_at_time: None,
+                timestamp: 1_700_000_000_000_000_000,
+            },
+            effective_fee: Some(Nat::from(10u64)),
+            parent_hash,
+        }
+    }
+
+    // Test vectors from the ICRC-3 specification.
+    #[test]
+    fn hash_value_matches_specification_vectors() {
+        assert_eq!(hex(&hash_value(&Value::Nat(Nat::from(42u64)))), "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1");
+        assert_eq!(hex(&hash_value(&Value::Int(Int::from(-42)))), "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc");
+        assert_eq!(hex(&hash_value(&Value::Text("Hello, World!".to_string()))), "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f");
+        assert_eq!(hex(&hash_value(&Value::Blob(vec![1, 2, 3, 4]))), "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a");
+
+        let array = Value::Array(vec![Value::Nat(Nat::from(3u64)), Value::Text("foo".to_string()), Value::Blob(vec![5, 6])]);
+        assert_eq!(hex(&hash_value(&array)), "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6");
+    }
+
+    #[test]
+    fn map_hash_ignores_field_order() {
+        let forward = Value::Map(vec![field("a", Value::Nat(Nat::from(1u64))), field("b", Value::Text("x".to_string()))]);
+        let reverse = Value::Map(vec![field("b", Value::Text("x".to_string())), field("a", Value::Nat(Nat::from(1u64)))]);
+        assert_eq!(hash_value(&forward), hash_value(&reverse));
+    }
+
+    #[test]
+    fn block_value_uses_generic_schema() {
+        let Value::Map(fields) = block_value(&transfer_block(None)) else { panic!("block must encode as a map") };
+        assert!(fields.contains(&field("btype", Value::Text("1xfer".to_string()))));
+        assert!(fields.contains(&field("fee", Value::Nat(Nat::from(10u64)))));
+        assert!(!fields.iter().any(|(name, _)| name == "phash"));
+    }
+
+    #[test]
+    fn parent_hash_chains_blocks() {
+        let first = hash_value(&block_value(&transfer_block(None)));
+        let second = block_value(&transfer_block(Some(first)));
+        let Value::Map(fields) = &second else { panic!("block must encode as a map") };
+        assert!(fields.contains(&field("phash", Value::Blob(first.to_vec()))));
+        assert_ne!(hash_value(&second), first);
+    }
+
+    #[test]
+    fn tip_certificate_commits_to_index_and_hash() {
+        let hash = [7u8; 32];
+        assert_ne!(tip_certified_data(1, &hash), tip_certified_data(2, &hash));
+        assert_ne!(tip_certified_data(1, &hash), tip_certified_data(1, &[8u8; 32]));
+
+        let tree = tip_hash_tree(300, &hash);
+        assert_eq!(&tree[..4], &[0xd9, 0xd9, 0xf7, 0x83]);
+        assert!(tree.ends_with(&[0x42, 0xac, 0x02]));
+    }
+}
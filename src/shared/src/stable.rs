@@ -0,0 +1,66 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _, Storable};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Memory 0 holds the heap state between pre_upgrade and post_upgrade; canisters number their stable structures from 1.
+const UPGRADES: u8 = 0;
+const WASM_PAGE_SIZE: u64 = 65536;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn memory(id: u8) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(MemoryId::new(id)))
+}
+
+// Stores any candid value in a stable structure.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Candid<T>(pub T);
+
+impl<T: CandidType + for<'de> Deserialize<'de>> Storable for Candid<T> {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(&self.0).expect("Failed to encode stable value"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Candid(Decode!(bytes.as_ref(), T).expect("Failed to decode stable value"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub fn save_state<T: CandidType>(state: &T) {
+    let bytes = Encode!(state).expect("Failed to encode state for upgrade");
+    let memory = memory(UPGRADES);
+    let pages = (bytes.len() as u64 + 8).div_ceil(WASM_PAGE_SIZE);
+    if memory.size() < pages && memory.grow(pages - memory.size()) < 0 {
+        ic_cdk::trap("Out of stable memory while saving state for upgrade");
+    }
+    memory.write(0, &(bytes.len() as u64).to_le_bytes());
+    memory.write(8, &bytes);
+}
+
+// Returns None when nothing was saved, e.g. when upgrading a canister that was installed but never written to.
+pub fn restore_state<T: CandidType + for<'de> Deserialize<'de>>() -> Option<T> {
+    let memory = memory(UPGRADES);
+    if memory.size() == 0 {
+        return None;
+    }
+    let mut len = [0u8; 8];
+    memory.read(0, &mut len);
+    let len = u64::from_le_bytes(len) as usize;
+    if len == 0 {
+        return None;
+    }
+    let mut bytes = vec![0u8; len];
+    memory.read(8, &mut bytes);
+    Some(Decode!(&bytes, T).expect("Failed to decode state saved before upgrade"))
+}
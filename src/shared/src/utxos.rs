@@ -0,0 +1,29 @@
+use ic_cdk::api::management_canister::bitcoin::{GetUtxosResponse, Utxo, UtxoFilter};
+
+// Paging of bitcoin_get_utxos as served by the local Bitcoin mock, kept here so canisters that read UTXOs
+// can run their unit tests against the same pages the mock serves.
+pub const PAGE_SIZE: usize = 100;
+
+// Pages are addressed by their starting offset, encoded as the little-endian bytes of a u32.
+pub fn page(utxos: &[Utxo], tip_height: u32, filter: Option<UtxoFilter>) -> GetUtxosResponse {
+    let (start, min_confirmations) = match filter {
+        Some(UtxoFilter::Page(page)) => {
+            let bytes: [u8; 4] = page.as_slice().try_into().unwrap_or_default();
+            (u32::from_le_bytes(bytes) as usize, 0)
+        },
+        Some(UtxoFilter::MinConfirmations(min)) => (0, min),
+        None => (0, 0),
+    };
+
+    let confirmed: Vec<&Utxo> = utxos
+        .iter()
+        .filter(|utxo| tip_height.saturating_sub(utxo.height).saturating_add(1) >= min_confirmations)
+        .collect();
+    let end = confirmed.len().min(start + PAGE_SIZE);
+    GetUtxosResponse {
+        utxos: confirmed.get(start..end).unwrap_or_default().iter().map(|utxo| (*utxo).clone()).collect(),
+        tip_block_hash: vec![0; 32],
+        tip_height,
+        next_page: (end < confirmed.len()).then(|| (end as u32).to_le_bytes().to_vec()),
+    }
+}